@@ -0,0 +1,117 @@
+use crate::sanitize::sanitize;
+
+/// A mailbox from a header like From, "Mr Apa <apa@bep.com>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub name: Option<String>,
+    pub addr: String,
+}
+
+// Split on the commas between addresses, not the ones in quotes, comments or <>
+fn split_list(header: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in header.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '(' | '<' if !quoted => depth += 1,
+            ')' | '>' if !quoted && depth > 0 => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                parts.push(&header[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    parts.push(&header[start..]);
+    parts.into_iter().map(|x| x.trim()).filter(|x| !x.is_empty()).collect()
+}
+
+// "Apa, Bepa" -> Apa, Bepa
+fn unquote(str: &str) -> String {
+    let str = str.trim();
+    match str.strip_prefix('"').and_then(|x| x.strip_suffix('"')) {
+        Some(inner) => inner.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => str.to_string(),
+    }
+}
+
+fn name(str: &str) -> Option<String> {
+    let name = sanitize(&unquote(str));
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+impl Address {
+    /// Parse a single mailbox: Name <addr>, <addr>, addr or addr (Name)
+    pub fn parse(mailbox: &str) -> Address {
+        let mailbox = mailbox.trim();
+        if let (Some(open), Some(close)) = (mailbox.rfind('<'), mailbox.rfind('>')) {
+            if open < close {
+                return Address {
+                    name: name(&mailbox[..open]),
+                    addr: sanitize(&mailbox[open + 1..close]),
+                }
+            }
+        }
+        if let (Some(open), Some(close)) = (mailbox.find('('), mailbox.rfind(')')) {
+            if open < close {
+                return Address {
+                    name: name(&mailbox[open + 1..close]),
+                    addr: sanitize(&mailbox[..open]),
+                }
+            }
+        }
+        Address { name: None, addr: sanitize(mailbox) }
+    }
+
+    /// All addresses in a header like To or Cc
+    pub fn parse_list(header: &str) -> Vec<Address> {
+        split_list(header).into_iter().map(Address::parse).collect()
+    }
+
+    /// The name if there is one, otherwise the part of the address before the @
+    pub fn short(&self) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => self.addr.split('@').next().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mailboxes() {
+        let addr = Address::parse("Mr Apa <apa@bep.com>");
+        assert_eq!(addr.name.as_deref(), Some("Mr Apa"));
+        assert_eq!(addr.addr, "apa@bep.com");
+        assert_eq!(Address::parse("apa@bep.com").short(), "apa");
+        assert_eq!(Address::parse("apa@bep.com (Mr Apa)").short(), "Mr Apa");
+        assert_eq!(Address::parse("\"Apa, \\\"Mr\\\"\" <apa@bep.com>").short(), "Apa, \"Mr\"");
+        assert_eq!(Address::parse("<apa@bep.com>").name, None);
+    }
+
+    #[test]
+    fn encoded_words() {
+        let addr = Address::parse("=?utf-8?q?Andr=C3=A9?= <andre@bep.com>");
+        assert_eq!(addr.short(), "André");
+    }
+
+    #[test]
+    fn lists() {
+        let list = Address::parse_list("\"Apa, Bepa\" <apa@bep.com>, cepa@bep.com,, Depa <d@bep.com>");
+        let names: Vec<String> = list.iter().map(|x| x.short()).collect();
+        assert_eq!(names, vec!["Apa, Bepa", "cepa", "Depa"]);
+    }
+}
@@ -0,0 +1,30 @@
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+
+/// Hands out a generation for every new query. A query is cancelled as
+/// soon as a newer generation has been handed out.
+#[derive(Clone, Debug, Default)]
+pub struct Generations(Arc<AtomicU64>);
+
+#[derive(Clone, Debug)]
+pub struct Cancel {
+    latest: Arc<AtomicU64>,
+    generation: u64,
+}
+
+impl Generations {
+    pub fn new() -> Generations {
+        Generations::default()
+    }
+
+    /// Start a new generation, cancelling every query that is still running.
+    pub fn next(&self) -> Cancel {
+        let generation = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+        Cancel { latest: self.0.clone(), generation }
+    }
+}
+
+impl Cancel {
+    pub fn cancelled(&self) -> bool {
+        self.latest.load(Ordering::SeqCst) != self.generation
+    }
+}
@@ -0,0 +1,138 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use serde::{Deserialize, de::Error as _};
+
+use crate::{Result, Error, highlight::Highlight};
+
+/// Defaults for the command line flags, the flags win over the config.
+/// Read from $XDG_CONFIG_HOME/nm-livesearch/config.toml
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(flatten)]
+    pub defaults: Preset,
+    // named presets picked with --style, like [styles.compact]
+    #[serde(default)]
+    pub styles: HashMap<String, Preset>,
+    // saved searches, used as @name or query:name
+    #[serde(default)]
+    pub queries: HashMap<String, String>,
+    // what's left, deny_unknown_fields doesn't work with flatten
+    #[serde(flatten)]
+    unknown: HashMap<String, toml::Value>,
+}
+
+/// The settings of the config, or of a named style on top of them
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    pub entry_fmt: Option<String>,
+    pub response_fmt: Option<String>,
+    pub date_format: Option<String>,
+    pub humanize_limit: Option<i64>,
+    pub sort: Option<String>,
+    pub highlight: Option<Highlight>,
+}
+
+impl Preset {
+    // Fill in what isn't set from other
+    fn or(self, other: Preset) -> Preset {
+        Preset {
+            entry_fmt: self.entry_fmt.or(other.entry_fmt),
+            response_fmt: self.response_fmt.or(other.response_fmt),
+            date_format: self.date_format.or(other.date_format),
+            humanize_limit: self.humanize_limit.or(other.humanize_limit),
+            sort: self.sort.or(other.sort),
+            highlight: self.highlight.or(other.highlight),
+        }
+    }
+}
+
+impl Config {
+    /// Where the config file is, if there is one
+    pub fn path() -> Option<PathBuf> {
+        xdg::BaseDirectories::with_prefix("nm-livesearch").find_config_file("config.toml")
+    }
+
+    /// Read the config from path, or the default file. No default file is an empty config.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path.map(Path::to_path_buf).or_else(Config::path) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let str = fs::read_to_string(path)?;
+        Config::parse(&str)
+    }
+
+    pub fn parse(str: &str) -> Result<Config> {
+        let config: Config = toml::from_str(str).map_err(Error::InvalidConfig)?;
+        if let Some(key) = config.unknown.keys().min() {
+            return Err(Error::InvalidConfig(toml::de::Error::custom(format!("unknown field `{}`", key))))
+        }
+        Ok(config)
+    }
+
+    /// The settings to use, the named style on top of the defaults
+    pub fn style(mut self, name: Option<&str>) -> Result<Preset> {
+        let preset = match name {
+            Some(name) => self.styles.remove(name).ok_or_else(|| Error::UnknownStyle(name.to_string()))?,
+            None => Preset::default(),
+        };
+        Ok(preset.or(self.defaults))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(r#"
+            entry_fmt = "{date} {from:25} {subject}"
+            humanize_limit = 2
+
+            [[highlight]]
+            name = "unread"
+            tags = ["unread"]
+
+            [[highlight]]
+            name = "github"
+            from = { glob = "*@github.com" }
+        "#).unwrap();
+        assert_eq!(config.defaults.entry_fmt.as_deref(), Some("{date} {from:25} {subject}"));
+        assert_eq!(config.defaults.humanize_limit, Some(2));
+        assert_eq!(config.defaults.sort, None);
+        assert_eq!(config.defaults.highlight.unwrap().rules.len(), 2);
+        assert!(config.styles.is_empty());
+        assert!(config.queries.is_empty());
+        assert_eq!(Config::parse("sort = 1").unwrap_err().code(), "invalid-config");
+        assert_eq!(Config::parse("sorting = \"newest\"").unwrap_err().code(), "invalid-config");
+        let error = Config::parse("sort = \"newest\"\n[styles.wide]\nsorting = \"oldest\"").unwrap_err();
+        assert!(error.to_string().contains("sorting"), "{}", error);
+    }
+
+    #[test]
+    fn styles() {
+        let config = || Config::parse(r#"
+            entry_fmt = "{date} {from:25} {subject}"
+            sort = "newest"
+
+            [styles.patches]
+            entry_fmt = "{date} {from_short:15} {subject}"
+            highlight = { subject = { regex = "^\\[PATCH" } }
+
+            [styles.oldest]
+            sort = "oldest"
+        "#).unwrap();
+        let style = config().style(Some("patches")).unwrap();
+        assert_eq!(style.entry_fmt.as_deref(), Some("{date} {from_short:15} {subject}"));
+        assert_eq!(style.sort.as_deref(), Some("newest"));
+        assert!(style.highlight.is_some());
+        let style = config().style(Some("oldest")).unwrap();
+        assert_eq!(style.entry_fmt.as_deref(), Some("{date} {from:25} {subject}"));
+        assert_eq!(style.sort.as_deref(), Some("oldest"));
+        let style = config().style(None).unwrap();
+        assert_eq!(style.sort.as_deref(), Some("newest"));
+        assert_eq!(config().style(Some("wide")).unwrap_err().code(), "unknown-style");
+    }
+}
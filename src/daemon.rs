@@ -0,0 +1,237 @@
+use std::{env, io::{self, BufRead}, fs, path::{Path, PathBuf}, process, thread, time::{Duration, Instant, SystemTime}};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use chrono::{DateTime, Utc};
+use notmuch::{Database, Sort};
+use serde::Deserialize;
+
+use crate::{Result, Error, highlight::Highlight, markup::Markup, queries::Queries, runtime::{Runtime, Templ}, serve, user::User};
+
+// How often we check if the database has changed under us
+static RECHECK: Duration = Duration::from_secs(1);
+// How long to wait before accepting clients again after it failed
+static RETRY: Duration = Duration::from_millis(100);
+
+/// Everything needed to (re)open the database
+pub struct Source {
+    pub db_path: Option<PathBuf>,
+    pub conf_path: Option<PathBuf>,
+    pub profile: Option<String>,
+}
+
+// When the index and the notmuch config were last written
+#[derive(Debug, PartialEq, Eq)]
+struct Stamp {
+    index: Option<SystemTime>,
+    config: Option<SystemTime>,
+}
+
+fn modified(path: Option<PathBuf>) -> Option<SystemTime> {
+    path.and_then(|path| fs::metadata(path).ok()).and_then(|meta| meta.modified().ok())
+}
+
+impl Source {
+    pub fn open(&self) -> Result<Database> {
+        let db = Database::open_with_config(self.db_path.as_ref(), notmuch::DatabaseMode::ReadOnly,
+            self.conf_path.as_ref(), self.profile.as_deref())?;
+        Ok(db)
+    }
+
+    fn profile(&self) -> String {
+        self.profile.clone()
+            .or_else(|| env::var("NOTMUCH_PROFILE").ok())
+            .unwrap_or_else(|| "default".to_string())
+    }
+
+    // The notmuch config in use, looked up the same way as notmuch does
+    fn config_file(&self) -> Option<PathBuf> {
+        if let Some(path) = self.conf_path.clone().or_else(|| env::var_os("NOTMUCH_CONFIG").map(PathBuf::from)) {
+            return Some(path)
+        }
+        let profile = self.profile();
+        let dotfile = match profile.as_str() {
+            "default" => ".notmuch-config".to_string(),
+            profile => format!(".notmuch-config.{}", profile),
+        };
+        xdg::BaseDirectories::with_prefix("notmuch").find_config_file(Path::new(&profile).join("config"))
+            .or_else(|| home::home_dir().map(|home| home.join(dotfile)).filter(|path| path.exists()))
+    }
+
+    // The xapian index of db, in the mail root or in the XDG data dir
+    fn index_dir(&self, db: &Database) -> Option<PathBuf> {
+        let dir = db.path().join(".notmuch").join("xapian");
+        if dir.exists() {
+            return Some(dir)
+        }
+        xdg::BaseDirectories::with_prefix("notmuch").find_data_file(Path::new(&self.profile()).join("xapian"))
+    }
+
+    // Cheap to check, unlike opening the database again
+    fn stamp(&self, db: &Database) -> Stamp {
+        Stamp {
+            index: modified(self.index_dir(db)),
+            config: modified(self.config_file()),
+        }
+    }
+}
+
+// The settings every worker makes its runtime from, the database
+// and what is read from it belong to the worker
+struct Shared {
+    templ: Templ,
+    sort: Sort,
+    highlight: Option<Highlight>,
+    date_format: String,
+    humanize_range: DateTime<Utc>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    markup: Markup,
+    queries: Queries,
+}
+
+impl Shared {
+    fn new(runtime: Runtime) -> Shared {
+        Shared {
+            templ: runtime.templ,
+            sort: runtime.sort,
+            highlight: runtime.highlight,
+            date_format: runtime.date_format,
+            humanize_range: runtime.humanize_range,
+            limit: runtime.limit,
+            offset: runtime.offset,
+            markup: runtime.markup,
+            queries: runtime.queries,
+        }
+    }
+
+    // The user and the saved searches are read from the notmuch config again
+    fn runtime(&self, db: Database) -> Result<Runtime> {
+        let user = User::load(&db)?;
+        let queries = self.queries.reload(&db)?;
+        let mut runtime = Runtime {
+            db,
+            templ: self.templ.clone(),
+            sort: self.sort,
+            highlight: self.highlight.clone(),
+            date_format: self.date_format.clone(),
+            humanize_range: self.humanize_range,
+            limit: self.limit,
+            offset: self.offset,
+            cancel: None,
+            user,
+            markup: self.markup,
+            queries,
+        };
+        runtime.prepare_highlight()?;
+        Ok(runtime)
+    }
+}
+
+// A read-only database is a snapshot, reopen it if notmuch has written to
+// the index or the config since we opened it.
+fn refresh(runtime: &mut Runtime, shared: &Shared, source: &Source, stamp: &mut Stamp) -> Result<()> {
+    let new = source.stamp(&runtime.db);
+    if new != *stamp {
+        let db = source.open()?;
+        *stamp = source.stamp(&db);
+        *runtime = shared.runtime(db)?;
+    }
+    Ok(())
+}
+
+// Answers a single client, with its own database, until it goes away.
+// A client only cancels its own queries.
+fn worker(shared: &Shared, source: &Source, stream: UnixStream) -> Result<()> {
+    let input = io::BufReader::new(stream.try_clone()?);
+    let mut writer = io::BufWriter::new(&stream);
+    let opened = source.open().and_then(|db| Ok((source.stamp(&db), shared.runtime(db)?)));
+    let result = match opened {
+        Ok((mut stamp, mut runtime)) => {
+            let mut checked = Instant::now();
+            serve::serve_with(&mut runtime, input, &mut writer, |runtime| {
+                if checked.elapsed() < RECHECK {
+                    return Ok(())
+                }
+                checked = Instant::now();
+                refresh(runtime, shared, source, &mut stamp)
+            })
+        },
+        Err(e) => serve::respond(&serde_json::Value::Null, Err(e), &mut writer),
+    };
+    // Ends the request reader too, if it's still waiting for input
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    result
+}
+
+/// Listen on a unix socket and answer requests from any number of clients.
+/// Every client gets its own worker, with its own database, so a slow
+/// query only holds up the client that asked for it.
+pub fn daemon(runtime: Runtime, source: &Source, socket: &Path) -> Result<()> {
+    // A socket left over from an earlier daemon
+    if socket.exists() && UnixStream::connect(socket).is_err() {
+        fs::remove_file(socket)?;
+    }
+    // The daemon runs until it's stopped, take the socket with it
+    let path = socket.to_path_buf();
+    ctrlc::set_handler(move || {
+        let _ = fs::remove_file(&path);
+        process::exit(0)
+    }).map_err(io::Error::other)?;
+    let listener = UnixListener::bind(socket)?;
+    // The database we were started with is closed, every worker opens its own
+    let shared = Shared::new(runtime);
+
+    thread::scope(|s| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                // Like running out of file descriptors, wait for clients to go away
+                Err(_) => {
+                    thread::sleep(RETRY);
+                    continue
+                },
+            };
+            // A worker's errors, like a client going away, only end that worker
+            let (shared, source) = (&shared, source);
+            s.spawn(move || worker(shared, source, stream));
+        }
+    });
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct RemoteError {
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Reply {
+    result: Option<serde_json::Value>,
+    error: Option<RemoteError>,
+    #[serde(default)]
+    done: bool,
+}
+
+/// Send a single request to a daemon and print the results
+/// the same way as running the command directly.
+pub fn client<W>(socket: &Path, request: &serde_json::Value, writer: &mut W) -> Result<()>
+where W: io::Write {
+    let mut stream = UnixStream::connect(socket)?;
+    serde_json::to_writer(&mut stream, request)?;
+    io::Write::write_all(&mut stream, b"\n")?;
+
+    for line in io::BufReader::new(stream).lines() {
+        let reply: Reply = serde_json::from_str(&line?)?;
+        if let Some(result) = reply.result {
+            serde_json::to_writer(&mut *writer, &result)?;
+            writeln!(writer)?;
+        }
+        if let Some(error) = reply.error {
+            return Err(Error::Daemon(error.message))
+        }
+        if reply.done {
+            break;
+        }
+    }
+    Ok(())
+}
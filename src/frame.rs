@@ -0,0 +1,127 @@
+use std::{io, time::Instant};
+
+use serde::Serialize;
+
+use crate::{Result, Error, runtime::Runtime};
+
+/// Counts the lines (results) written through it
+pub struct Counter<'a, W: io::Write> {
+    pub lines: usize,
+    writer: &'a mut W,
+}
+
+impl<'a, W: io::Write> Counter<'a, W> {
+    pub fn new(writer: &'a mut W) -> Counter<'a, W> {
+        Counter { lines: 0, writer }
+    }
+}
+
+impl<'a, W: io::Write> io::Write for Counter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.lines += buf[..n].iter().filter(|c| **c == b'\n').count();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    // None if the command doesn't run a plain search, like messages-before
+    pub total: Option<u32>,
+    pub emitted: usize,
+    pub skipped: usize,
+    pub elapsed_ms: u128,
+    pub query: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorRecord {
+    pub code: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Record<'a> {
+    Summary(&'a Summary),
+    Error(&'a ErrorRecord),
+}
+
+impl Summary {
+    /// Summarize a finished command. Depending on the command the total is
+    /// the number of matching messages or threads. Only the commands that
+    /// page through them with limit and offset have skipped any.
+    pub fn new(runtime: &Runtime, method: &str, query: &str, emitted: usize, start: Instant) -> Result<Summary> {
+        let (total, paged) = match method {
+            "messages" | "show-message" => (Some(runtime.count(query, false)?), true),
+            "threads" | "show-tree" => (Some(runtime.count(query, true)?), true),
+            "show-single-tree" | "show-thread" => (Some(runtime.count(query, true)?), false),
+            _ => (None, false),
+        };
+        let offset = runtime.offset.unwrap_or(0);
+        let skipped = match total {
+            Some(total) if paged => offset.min(total as usize),
+            _ => 0,
+        };
+        Ok(Summary {
+            total,
+            emitted,
+            skipped,
+            elapsed_ms: start.elapsed().as_millis(),
+            query: query.to_string(),
+        })
+    }
+
+    pub fn write<W>(&self, writer: &mut W) -> Result<()>
+    where W: io::Write {
+        serde_json::to_writer(&mut *writer, &Record::Summary(self))?;
+        writeln!(writer)?;
+        Ok(())
+    }
+}
+
+impl ErrorRecord {
+    pub fn new(err: &Error) -> ErrorRecord {
+        ErrorRecord { code: err.code(), message: err.to_string() }
+    }
+
+    pub fn write<W>(&self, writer: &mut W) -> Result<()>
+    where W: io::Write {
+        serde_json::to_writer(&mut *writer, &Record::Error(self))?;
+        writeln!(writer)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use io::Write;
+
+    use super::*;
+
+    #[test]
+    fn records() {
+        let mut out = Vec::new();
+        let mut counter = Counter::new(&mut out);
+        counter.write_all(b"{\"id\": 1}\n{\"id\": 2}\n").unwrap();
+        assert_eq!(counter.lines, 2);
+        let summary = Summary { total: Some(3), emitted: counter.lines, skipped: 1, elapsed_ms: 5, query: "tag:inbox".to_string() };
+        summary.write(&mut out).unwrap();
+        ErrorRecord::new(&Error::UnknownMethod("nope".to_string())).write(&mut out).unwrap();
+
+        let lines: Vec<serde_json::Value> = out.split(|c| *c == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[2], serde_json::json!({
+            "summary": {"total": 3, "emitted": 2, "skipped": 1, "elapsed_ms": 5, "query": "tag:inbox"},
+        }));
+        assert_eq!(lines[3]["error"]["code"], "unknown-method");
+        assert_eq!(lines[3]["error"]["message"], Error::UnknownMethod("nope".to_string()).to_string());
+    }
+}
@@ -1,58 +1,329 @@
-use serde::Deserialize;
+use std::{collections::HashSet, convert::TryFrom, fmt};
 
-use crate::{Result, time::show_time};
+use chrono::Utc;
+use notmuch::Database;
+use serde::{Deserialize, Deserializer, Serialize, de::{MapAccess, SeqAccess, Visitor, value::{MapAccessDeserializer, SeqAccessDeserializer}}};
 
-#[derive(Deserialize, Debug)]
+use crate::{Result, address::Address, markup::Markup, pattern::{Mode, Pattern}, queries::Queries, sanitize::sanitize, time::{show_time, When}, user::User, template::{Field, Rendered}};
+
+/// Named rules, a message is highlighted if any of them match. A single
+/// object of criteria is one rule without a name.
+#[derive(Debug, Clone)]
 pub struct Highlight {
-    // mode (if we should have and/or semantics)
-    pub id: Option<String>,
-    pub date: Option<String>,
+    pub rules: Vec<Rule>,
+}
+
+impl<'de> Deserialize<'de> for Highlight {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Highlight, D::Error>
+    where D: Deserializer<'de> {
+        deserializer.deserialize_any(HighlightVisitor)
+    }
+}
+
+// A list of rules or a single rule. Parsed as the one that was given,
+// so its errors, like an unknown criterion, are the ones reported.
+struct HighlightVisitor;
+
+impl<'de> Visitor<'de> for HighlightVisitor {
+    type Value = Highlight;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a rule or a list of rules")
+    }
+
+    fn visit_seq<A>(self, seq: A) -> std::result::Result<Highlight, A::Error>
+    where A: SeqAccess<'de> {
+        let rules = Vec::deserialize(SeqAccessDeserializer::new(seq))?;
+        Ok(Highlight { rules })
+    }
+
+    fn visit_map<A>(self, map: A) -> std::result::Result<Highlight, A::Error>
+    where A: MapAccess<'de> {
+        let rule = Rule::deserialize(MapAccessDeserializer::new(map))?;
+        Ok(Highlight { rules: vec![rule] })
+    }
+}
+
+/// Criteria and the name of the rule, next to them: {"name": "boss", "from": "boss@bep.com"}
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "Criteria")]
+pub struct Rule {
+    pub name: Option<String>,
+    pub criteria: Criteria,
+}
+
+impl TryFrom<Criteria> for Rule {
+    type Error = String;
+
+    fn try_from(mut criteria: Criteria) -> std::result::Result<Rule, String> {
+        let name = criteria.name.take();
+        if criteria.named() {
+            return Err("only a rule can have a name, not the criteria in all, any or not".to_string())
+        }
+        // A half-written rule would highlight everything
+        if criteria.is_empty() {
+            return Err(format!("the rule {}has no criteria, it would match everything",
+                name.as_ref().map(|name| format!("{} ", name)).unwrap_or_default()))
+        }
+        Ok(Rule { name, criteria })
+    }
+}
+
+/// All the set fields have to match, and all of `all`, one of `any` and not `not`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Criteria {
+    // the name of a rule, moved to Rule
+    name: Option<String>,
+    pub id: Option<Pattern>,
+    pub date: Option<Pattern>,
+    // on or after, and before, a date like "2021-11-09", "yesterday" or "2w"
+    pub date_after: Option<When>,
+    pub date_before: Option<When>,
     pub num: Option<i32>,
     pub total: Option<i32>,
-    pub from: Option<String>,
+    pub from: Option<Pattern>,
     // can't do this one, it's extremely slow
     // to: Option<String>,
-    pub subject: Option<String>,
-    pub tags: Option<Vec<String>>,
+    pub subject: Option<Pattern>,
+    pub tags: Option<Vec<Pattern>>,
     pub matched: Option<bool>,
     pub excluded: Option<bool>,
+    // sent by one of the addresses in the notmuch config
+    pub from_me: Option<bool>,
+    // a notmuch query, like "tag:unread and from:boss"
+    pub query: Option<String>,
+    // the messages and threads the query matched, filled in by prepare
+    #[serde(skip)]
+    ids: Option<HashSet<String>>,
+    #[serde(skip)]
+    threads: Option<HashSet<String>>,
+    #[serde(default)]
+    pub all: Vec<Criteria>,
+    #[serde(default)]
+    pub any: Vec<Criteria>,
+    pub not: Option<Box<Criteria>>,
+}
+
+/// What made a message highlighted
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Match {
+    // the names of the rules that matched
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<String>,
+    // the criteria that were set, all of them matched
+    pub criteria: Vec<&'static str>,
+    // where the matched fields are in the entry
+    pub ranges: Vec<Range>,
+}
+
+/// A field in the entry that a criterion matched, start and end are byte offsets
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Range {
+    pub start: usize,
+    pub end: usize,
+    pub criterion: &'static str,
+}
+
+// If the template field shows what the criterion matched on
+fn shows(criterion: &str, field: &Field) -> bool {
+    match criterion {
+        "id" => matches!(field, Field::MessageId),
+        "date" | "date_after" | "date_before" => matches!(field, Field::Date | Field::Humanized),
+        "num" => matches!(field, Field::Index),
+        "total" => matches!(field, Field::Total),
+        "from" | "from_me" => matches!(field, Field::From | Field::FromName | Field::FromAddr | Field::FromShort | Field::FromOrTo),
+        "subject" => matches!(field, Field::Subject),
+        "tags" => matches!(field, Field::Tags),
+        "matched" => matches!(field, Field::Matched),
+        "excluded" => matches!(field, Field::Excluded),
+        _ => false,
+    }
+}
+
+impl Match {
+    /// Find the fields the criteria matched on in a rendered entry, as they
+    /// will be in the entry once the markup is applied
+    pub fn locate(&mut self, rendered: &Rendered, markup: Markup) {
+        self.ranges = rendered.spans.iter()
+            .filter(|span| span.start < span.end)
+            .flat_map(|span| self.criteria.iter()
+                .filter(|criterion| span.fields.iter().any(|field| shows(criterion, field)))
+                .map(|criterion| {
+                    let (start, end) = markup.shift(rendered, span.start, span.end);
+                    Range { start, end, criterion }
+                }))
+            .collect();
+    }
 }
 
 macro_rules! match_ret {
-    ( $x:expr ) => {
+    ( $criteria:expr, $name:expr, $x:expr ) => {
         {
            if !$x {
                return Ok(false)
            }
+           $criteria.push($name);
         }
     };
 }
 
+// What we highlight, a message with its number and the total, or a thread
+enum Item<'a> {
+    Message(&'a notmuch::Message, i32, i32),
+    Thread(&'a notmuch::Thread),
+}
+
+impl<'a> Item<'a> {
+    fn newest(&self) -> i64 {
+        match self {
+            Item::Message(message, ..) => message.date(),
+            Item::Thread(thread) => thread.newest_date(),
+        }
+    }
+
+    fn oldest(&self) -> i64 {
+        match self {
+            Item::Message(message, ..) => message.date(),
+            Item::Thread(thread) => thread.oldest_date(),
+        }
+    }
+}
+
+impl Highlight {
+    /// The match if any of the rules match the message, None otherwise
+    pub fn message(&self, message: &notmuch::Message, num: i32, total: i32, user: &User) -> Result<Option<Match>> {
+        self.item(&Item::Message(message, num, total), user)
+    }
+
+    /// The match if any of the rules match the thread, None otherwise
+    pub fn thread(&self, thread: &notmuch::Thread, user: &User) -> Result<Option<Match>> {
+        self.item(&Item::Thread(thread), user)
+    }
+
+    fn item(&self, item: &Item, user: &User) -> Result<Option<Match>> {
+        let mut matched: Option<Match> = None;
+        for rule in &self.rules {
+            let mut criteria = Vec::new();
+            if !rule.criteria.matches(item, user, &mut criteria)? {
+                continue;
+            }
+            let matched = matched.get_or_insert_with(Match::default);
+            matched.rules.extend(rule.name.clone());
+            for criterion in criteria {
+                if !matched.criteria.contains(&criterion) {
+                    matched.criteria.push(criterion);
+                }
+            }
+        }
+        Ok(matched)
+    }
+}
+
 impl Highlight {
-    pub fn message<'a>(&self, message: &notmuch::Message, num: i32, total: i32) -> Result<bool> {
+    /// Run the queries of the rules, once before we match any messages
+    pub fn prepare(&mut self, db: &Database, queries: &Queries) -> Result<()> {
+        for rule in &mut self.rules {
+            rule.criteria.prepare(db, queries)?;
+        }
+        Ok(())
+    }
+}
+
+impl Criteria {
+    /// If the set fields are empty, so it would match everything
+    pub fn is_empty(&self) -> bool {
+        self.id.is_none() && self.date.is_none() && self.date_after.is_none() && self.date_before.is_none()
+            && self.num.is_none() && self.total.is_none()
+            && self.from.is_none() && self.subject.is_none() && self.tags.is_none()
+            && self.matched.is_none() && self.excluded.is_none() && self.from_me.is_none()
+            && self.all.iter().all(Criteria::is_empty) && self.any.iter().all(Criteria::is_empty)
+            && self.query.is_none() && self.not.is_none()
+    }
+
+    // If any of the nested criteria has a name
+    fn named(&self) -> bool {
+        self.all.iter().chain(&self.any).chain(self.not.as_deref())
+            .any(|criteria| criteria.name.is_some() || criteria.named())
+    }
+
+    fn prepare(&mut self, db: &Database, queries: &Queries) -> Result<()> {
+        if let Some(ref query) = self.query {
+            let query = db.create_query(&queries.expand(query))?;
+            let mut ids = HashSet::new();
+            let mut threads = HashSet::new();
+            for message in query.search_messages()? {
+                ids.insert(message.id().to_string());
+                threads.insert(message.thread_id().to_string());
+            }
+            self.ids = Some(ids);
+            self.threads = Some(threads);
+        }
+        for criteria in self.all.iter_mut().chain(self.any.iter_mut()).chain(self.not.as_deref_mut()) {
+            criteria.prepare(db, queries)?;
+        }
+        Ok(())
+    }
+
+    // Adds the names of the criteria that matched to `matched`, for `not` nothing is added
+    fn matches(&self, item: &Item, user: &User, matched: &mut Vec<&'static str>) -> Result<bool> {
+        let mut criteria = Vec::new();
         if let Some(ref mid) = self.id {
-            let id = message.id();
-            match_ret!(mid == id.as_ref());
+            let id = match item {
+                Item::Message(message, ..) => message.id(),
+                Item::Thread(thread) => thread.id(),
+            };
+            match_ret!(criteria, "id", mid.matches(&id, Mode::Exact));
         }
         // we convert date to string to a string
         // so we can match 2021-11-09 agains 2021-11 and get a match
         if let Some(ref mdate) = self.date {
-            let unix_date = message.date();
-            let date = show_time(unix_date, "%Y-%m-%d");
-            match_ret!(date.to_string().contains(mdate));
+            let date = show_time(item.newest(), "%Y-%m-%d")?;
+            match_ret!(criteria, "date", mdate.matches(&date.to_string(), Mode::Substring));
         }
+        // a thread is after a date if the newest message is, and before if the oldest is
+        if let Some(after) = self.date_after {
+            match_ret!(criteria, "date_after", item.newest() >= after.timestamp(Utc::now())?);
+        }
+        if let Some(before) = self.date_before {
+            match_ret!(criteria, "date_before", item.oldest() < before.timestamp(Utc::now())?);
+        }
+        // for a thread num is the number of messages that matched the search
         if let Some(mnum) = self.num {
-            match_ret!(mnum == num);
+            let num = match item {
+                Item::Message(_, num, _) => *num,
+                Item::Thread(thread) => thread.matched_messages(),
+            };
+            match_ret!(criteria, "num", mnum == num);
         }
         if let Some(mtotal) = self.total {
-            match_ret!(mtotal == total);
+            let total = match item {
+                Item::Message(_, _, total) => *total,
+                Item::Thread(thread) => thread.total_messages(),
+            };
+            match_ret!(criteria, "total", mtotal == total);
         }
         // when we do a match on email, the maddress need to be just the address:
         // so "apa@bep.com" would match "Mr Apa <apa@bep.com>" or "apa@bep.com"
-        // it can't handle idn
+        // it can't handle idn. A pattern matches the whole header, the address or the name,
+        // so the glob "*@github.com" matches "GitHub <noreply@github.com>"
         if let Some(ref mfrom) = self.from {
-            let from = message.header("From")?.unwrap_or_default();
-            match_ret!(from.contains(mfrom));
+            let found = match item {
+                Item::Message(message, ..) => {
+                    let header = message.header("From")?.unwrap_or_default();
+                    let from = sanitize(&header);
+                    let address = Address::parse(&header);
+                    mfrom.matches(&from, Mode::Substring)
+                        || mfrom.matches(&address.addr, Mode::Substring)
+                        || address.name.is_some_and(|name| mfrom.matches(&name, Mode::Substring))
+                },
+                // notmuch only keeps the names of the authors of a thread,
+                // matched and unmatched ones are joined by a "|"
+                Item::Thread(thread) => thread.authors().iter()
+                    .flat_map(|authors| authors.split('|'))
+                    .any(|author| mfrom.matches(sanitize(author).trim(), Mode::Substring)),
+            };
+            match_ret!(criteria, "from", found);
         }
 
         // Can't do this one! Since nm does't save the to address in the db
@@ -62,24 +333,140 @@ impl Highlight {
         // }
 
         if let Some(ref msubject) = self.subject {
-            let subject = message.header("Subject")?.unwrap_or_default();
-            match_ret!(msubject == subject.as_ref());
+            let subject = match item {
+                Item::Message(message, ..) => sanitize(&message.header("Subject")?.unwrap_or_default()),
+                Item::Thread(thread) => sanitize(&thread.subject()),
+            };
+            match_ret!(criteria, "subject", msubject.matches(&subject, Mode::Exact));
         }
         if let Some(ref mtags) = self.tags {
-            let tags: Vec<String> = message.tags().collect();
+            let tags: Vec<String> = match item {
+                Item::Message(message, ..) => message.tags().collect(),
+                Item::Thread(thread) => thread.tags().collect(),
+            };
             for mtag in mtags {
-                match_ret!(tags.contains(mtag));
+                match_ret!(criteria, "tags", tags.iter().any(|tag| mtag.matches(tag, Mode::Exact)));
             }
         }
+        // a thread matched if any of its messages did
         if let Some(mmatched) = self.matched {
-            let nmatched = message.get_flag(notmuch::MessageFlag::Match);
-            match_ret!(mmatched == nmatched);
+            let nmatched = match item {
+                Item::Message(message, ..) => message.get_flag(notmuch::MessageFlag::Match),
+                Item::Thread(thread) => thread.matched_messages() > 0,
+            };
+            match_ret!(criteria, "matched", mmatched == nmatched);
         }
+        // there is no excluded flag for a thread, so it never matches
         if let Some(mexclude) = self.excluded {
-            let exclude = message.get_flag(notmuch::MessageFlag::Excluded);
-            match_ret!(mexclude == exclude);
+            let exclude = match item {
+                Item::Message(message, ..) => Some(message.get_flag(notmuch::MessageFlag::Excluded)),
+                Item::Thread(_) => None,
+            };
+            match_ret!(criteria, "excluded", exclude == Some(mexclude));
+        }
+        // a thread is from me if the first message is
+        if let Some(mfrom_me) = self.from_me {
+            let from = match item {
+                Item::Message(message, ..) => message.header("From")?.unwrap_or_default().to_string(),
+                Item::Thread(thread) => match thread.toplevel_messages().next() {
+                    Some(message) => message.header("From")?.unwrap_or_default().to_string(),
+                    None => String::new(),
+                },
+            };
+            match_ret!(criteria, "from_me", mfrom_me == user.sent(&from));
+        }
+        // without prepare we don't know what the query matches, so nothing does.
+        // A thread matches if any of its messages does.
+        if self.query.is_some() {
+            let found = match item {
+                Item::Message(message, ..) => self.ids.as_ref().is_some_and(|ids| ids.contains(message.id().as_ref())),
+                Item::Thread(thread) => self.threads.as_ref().is_some_and(|threads| threads.contains(thread.id().as_ref())),
+            };
+            match_ret!(criteria, "query", found);
+        }
+
+        for all in &self.all {
+            if !all.matches(item, user, &mut criteria)? {
+                return Ok(false);
+            }
+        }
+        if !self.any.is_empty() {
+            let mut any = false;
+            for one in &self.any {
+                if one.matches(item, user, &mut criteria)? {
+                    any = true;
+                    break;
+                }
+            }
+            if !any {
+                return Ok(false);
+            }
+        }
+        if let Some(ref not) = self.not {
+            if not.matches(item, user, &mut Vec::new())? {
+                return Ok(false);
+            }
         }
 
-        return Ok(true);
+        matched.extend(criteria);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::{Template, mock::Mock};
+
+    #[test]
+    fn locate() {
+        let templ = Template::parse("{from_short:5}│{subject|tags}").unwrap();
+        let rendered = templ.render_spans(&Mock).unwrap();
+        let mut matched = Match { rules: Vec::new(), criteria: vec!["from", "tags", "num"], ranges: Vec::new() };
+        matched.locate(&rendered, Markup::Plain);
+        assert_eq!(matched.ranges, vec![
+            Range { start: 0, end: 5, criterion: "from" },
+            Range { start: 8, end: 13, criterion: "tags" },
+        ]);
+    }
+
+    #[test]
+    fn locate_ansi() {
+        let templ = Template::parse("{from|style=author}: {subject|style=nope} {index|style=count}").unwrap();
+        let rendered = templ.render_spans(&Mock).unwrap();
+        let mut matched = Match { rules: Vec::new(), criteria: vec!["from", "subject", "num"], ranges: Vec::new() };
+        matched.locate(&rendered, Markup::Ansi);
+        let (entry, _) = Markup::Ansi.apply(rendered);
+        let located: Vec<_> = matched.ranges.iter().map(|range| &entry[range.start..range.end]).collect();
+        assert_eq!(located, vec!["Apa", "Hello", "3"]);
+    }
+
+    #[test]
+    fn rules() {
+        let hl: Highlight = serde_json::from_str(r#"{"from": "apa@bep.com"}"#).unwrap();
+        assert_eq!(hl.rules.len(), 1);
+        assert_eq!(hl.rules[0].name, None);
+        let hl: Highlight = serde_json::from_str(r#"[
+            {"name": "boss", "from": "boss@bep.com"},
+            {"name": "todo", "any": [{"tags": ["unread"]}, {"tags": ["flagged"]}], "not": {"from_me": true}},
+            {"name": "unread", "all": [{}, {"tags": ["unread"]}]}
+        ]"#).unwrap();
+        let names: Vec<_> = hl.rules.iter().map(|rule| rule.name.as_deref().unwrap()).collect();
+        assert_eq!(names, vec!["boss", "todo", "unread"]);
+        assert_eq!(hl.rules[1].criteria.any.len(), 2);
+        assert!(!hl.rules[1].criteria.is_empty());
+        assert!(!hl.rules[2].criteria.is_empty());
+    }
+
+    #[test]
+    fn typos() {
+        let error = |json| serde_json::from_str::<Highlight>(json).unwrap_err().to_string();
+        assert!(error(r#"{"tgas": ["unread"]}"#).contains("unknown field `tgas`"));
+        assert!(error(r#"[{"name": "todo", "any": [{"tgas": ["unread"]}]}]"#).contains("unknown field `tgas`"));
+        assert!(error(r#"{"num": "three"}"#).contains("invalid type"));
+        assert!(error(r#"{"not": {"name": "nope"}}"#).contains("only a rule can have a name"));
+        assert!(error(r#"{"name": "nothing"}"#).contains("the rule nothing has no criteria"));
+        assert!(error(r#"[{"from": "boss@bep.com"}, {"all": [{}]}]"#).contains("has no criteria"));
+        assert!(error(r#""unread""#).contains("a rule or a list of rules"));
     }
 }
@@ -5,6 +5,17 @@ pub enum Error {
     SerdeErr(serde_json::Error),
     NmError(notmuch::Error),
     IoError(io::Error),
+    Cancelled,
+    UnknownMethod(String),
+    Daemon(String),
+    TemplateError(String),
+    InvalidSort(String),
+    InvalidHighlight(serde_json::Error),
+    InvalidDate(i64),
+    InvalidDateSpec(String),
+    InvalidMarkup(String),
+    InvalidConfig(toml::de::Error),
+    UnknownStyle(String),
 }
 
 impl fmt::Display for Error {
@@ -13,6 +24,17 @@ impl fmt::Display for Error {
             Error::SerdeErr(e) => <serde_json::Error as fmt::Display>::fmt(e, f),
             Error::NmError(e) => <notmuch::Error as fmt::Display>::fmt(e, f),
             Error::IoError(e) => <io::Error as fmt::Display>::fmt(e,f),
+            Error::Cancelled => write!(f, "Query was cancelled"),
+            Error::UnknownMethod(m) => write!(f, "Unknown method or missing params: {}", m),
+            Error::Daemon(e) => write!(f, "Daemon: {}", e),
+            Error::TemplateError(e) => write!(f, "Template: {}", e),
+            Error::InvalidSort(s) => write!(f, "Bad sort option: {}", s),
+            Error::InvalidHighlight(e) => write!(f, "Parsing json highlighting failed: {}", e),
+            Error::InvalidDate(d) => write!(f, "Couldn't convert timestamp {} to a date", d),
+            Error::InvalidDateSpec(d) => write!(f, "Bad date: {}, use a date like 2021-11-09, today, yesterday or 2w", d),
+            Error::InvalidMarkup(m) => write!(f, "Unknown markup: {}, use plain, ansi or ranges", m),
+            Error::InvalidConfig(e) => write!(f, "Parsing the config failed: {}", e),
+            Error::UnknownStyle(s) => write!(f, "No style named {} in the config", s),
         }
     }
 }
@@ -23,6 +45,38 @@ impl error::Error for Error {
             Error::SerdeErr(e) => Some(e),
             Error::NmError(e) => Some(e),
             Error::IoError(e) => Some(e),
+            Error::Cancelled => None,
+            Error::UnknownMethod(_) => None,
+            Error::Daemon(_) => None,
+            Error::TemplateError(_) => None,
+            Error::InvalidSort(_) => None,
+            Error::InvalidHighlight(e) => Some(e),
+            Error::InvalidDate(_) => None,
+            Error::InvalidDateSpec(_) => None,
+            Error::InvalidMarkup(_) => None,
+            Error::InvalidConfig(e) => Some(e),
+            Error::UnknownStyle(_) => None,
+        }
+    }
+}
+
+impl Error {
+    /// A stable name for the kind of error, for programs reading json errors
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::SerdeErr(_) => "json",
+            Error::NmError(_) => "notmuch",
+            Error::IoError(_) => "io",
+            Error::Cancelled => "cancelled",
+            Error::UnknownMethod(_) => "unknown-method",
+            Error::Daemon(_) => "daemon",
+            Error::TemplateError(_) => "template",
+            Error::InvalidSort(_) => "invalid-sort",
+            Error::InvalidHighlight(_) => "invalid-highlight",
+            Error::InvalidDate(_) | Error::InvalidDateSpec(_) => "invalid-date",
+            Error::InvalidMarkup(_) => "invalid-markup",
+            Error::InvalidConfig(_) => "invalid-config",
+            Error::UnknownStyle(_) => "unknown-style",
         }
     }
 }
@@ -53,3 +107,15 @@ pub mod runtime;
 pub mod highlight;
 pub mod time;
 pub mod ordered;
+pub mod serve;
+pub mod cancel;
+pub mod daemon;
+pub mod frame;
+pub mod template;
+pub mod address;
+pub mod sanitize;
+pub mod user;
+pub mod markup;
+pub mod pattern;
+pub mod config;
+pub mod queries;
@@ -1,35 +1,22 @@
-use std::io;
+use std::io::{self, Write};
+use std::process;
 extern crate home;
 extern crate notmuch;
 use chrono::Duration;
-use nm_livesearch::{Result, highlight::Highlight, runtime::{Runtime, Templ}};
+use nm_livesearch::{Result, Error, highlight::Highlight, runtime::{Runtime, Templ}, serve::serve, daemon::{daemon, client, Source}, frame::{Counter, Summary, ErrorRecord}, markup::Markup, user::User, config::Config, queries::Queries, time::check_format};
 use notmuch::Sort;
 extern crate chrono;
-use regex::*;
 
 use chrono::prelude::*;
 
 use std::path::PathBuf;
+use std::time::Instant;
 
-use clap::{Parser, Subcommand};
-
-static REGEXSTR: &str = r"\{([^:]*?):?(\d+)?\}";
-
-macro_rules! check_none {
-    ( $( $x:expr ),* ) => {
-        {
-            $(
-                if let Some(_) = $x {
-                    return false;
-                }  
-            )*
-            true
-        }
-    }
-}
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueSource};
+use clap_complete::{generate, Shell};
 
 fn check(hl: &Highlight) -> bool {
-    check_none!(hl.id, hl.date, hl.num, hl.total, hl.from, hl.subject, hl.tags, hl.matched, hl.excluded)
+    hl.rules.iter().all(|rule| rule.criteria.is_empty())
 }
 
 fn empty(hl: Option<Highlight>) -> Option<Highlight> {
@@ -42,19 +29,34 @@ fn empty(hl: Option<Highlight>) -> Option<Highlight> {
     return None;
 }
 
+// The defaults when neither the flags nor the config set them
+const SORT: &str = "newest";
+const HUMANIZE_LIMIT: i64 = 5;
+const ENTRY_FMT: &str = "{date} [{index:02}/{total:02}] {from:25}│ {subject}{?tags: ({tags})}";
+const RESPONSE_FMT: &str = "{date} [{index:02}/{total:02}] {from:25}│ {response}▶{?tags: ({tags})}";
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
     /// How to order the messages
-    #[clap(short, long, value_name = "oldest|newest")]
-    #[clap(default_value_t = String::from("newest"))]
+    #[clap(short, long, possible_values = &["oldest", "newest", "message-id", "unsorted"])]
+    #[clap(default_value = SORT)]
     sort: String,
 
     /// How many days backward should we humanize dates
-    #[clap(long)]
-    #[clap(default_value_t = 5)]
+    #[clap(long, default_value_t = HUMANIZE_LIMIT)]
     humanize_limit: i64,
 
+    /// Path to our config file [default: $XDG_CONFIG_HOME/nm-livesearch/config.toml]
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// A named preset from the [styles] of the config, with its own templates, dates,
+    /// sort and highlight. Not the same as the style groups of a template, {from|style=author}
+    #[clap(long, value_name = "NAME")]
+    style: Option<String>,
+
     /// Path to notmuch database
     #[clap(short, long, parse(from_os_str), value_name = "FILE")]
     db_path: Option<PathBuf>,
@@ -71,24 +73,26 @@ struct Cli {
     command: Commands,
 
     /// A template for how to print an email entry
-    #[clap(short, long)]
-    #[clap(default_value_t = String::from("{date} [{index:02}/{total:02}] {from:25}│ {subject} ({tags})"))]
+    #[clap(short, long, default_value = ENTRY_FMT)]
     entry_fmt: String,
 
     /// A template for how to print an email response in a thread
-    #[clap(short, long)]
-    #[clap(default_value_t = String::from("{date} [{index:02}/{total:02}] {from:25}│ {response}▶ ({tags})"))]
+    #[clap(short, long, default_value = RESPONSE_FMT)]
     response_fmt: String,
 
-    /// How to order the messages
-    #[clap(long)]
-    #[clap(default_value_t = String::from("%Y-%m-%d"))]
+    /// How to format dates
+    #[clap(long, default_value = DATE_FORMAT)]
     date_format: String,
 
     /// notmuch entry we want to match and highlight on
     #[clap(short, long)]
     highlight: Option<String>,
 
+    /// How to show the style groups of the templates, {from|style=author}
+    #[clap(long, possible_values = &["plain", "ansi", "ranges"])]
+    #[clap(default_value_t = String::from("plain"))]
+    markup: String,
+
     /// How many entries to print. Can stand for threads or emails.
     #[clap(short, long)]
     limit: Option<usize>,
@@ -96,112 +100,305 @@ struct Cli {
     /// How many to skip before we start to print. Can stand for threads or emails.
     #[clap(short, long)]
     offset: Option<usize>,
+
+    /// End the output with a summary record and print errors as json records
+    #[clap(long)]
+    framed: bool,
+
+    /// Print errors as a json object on stderr
+    #[clap(long)]
+    json_errors: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Messages {
+        /// Notmuch search terms, @name for a saved search
         #[clap(required = true)]
         search: Vec<String>,
     },
     Threads {
+        /// Notmuch search terms, @name for a saved search
         #[clap(required = true)]
         search: Vec<String>,
     },
     MessagesBefore {
+        /// The message id
         #[clap(required = true)]
         id: String,
+        /// Only the messages matching these search terms
         search: Vec<String>,
     },
     MessagesAfter {
+        /// The message id
         #[clap(required = true)]
         id: String,
+        /// Only the messages matching these search terms
         search: Vec<String>,
     },
     ShowTree {
+        /// Notmuch search terms, @name for a saved search
         #[clap(required = true)]
         search: Vec<String>,
     },
     ShowSingleTree {
+        /// Notmuch search terms, @name for a saved search
         #[clap(required = true)]
         search: Vec<String>,
     },
     ShowMessage {
+        /// Notmuch search terms, @name for a saved search
         #[clap(required = true)]
         search: Vec<String>,
     },
     ShowThread {
+        /// Notmuch search terms, @name for a saved search
         #[clap(required = true)]
         search: Vec<String>,
-    }
+    },
+    /// Count the messages and threads, and the messages with each tag
+    Count {
+        /// Notmuch search terms, @name for a saved search
+        search: Vec<String>,
+    },
+    /// List the saved searches, from our config and the notmuch config, with how many
+    /// messages and threads they match
+    ListQueries,
+    /// Keep the database open and answer json requests read from stdin, one per line
+    Serve,
+    /// Keep the database open and answer json requests from clients on a unix socket
+    Daemon {
+        /// Path to the unix socket
+        #[clap(long, parse(from_os_str), value_name = "FILE")]
+        socket: PathBuf,
+    },
+    /// Run a command, such as show-message, through a running daemon. The daemon's
+    /// templates, sort and config are used, only --limit, --offset and --highlight are sent
+    Client {
+        /// Path to the unix socket
+        #[clap(long, parse(from_os_str), value_name = "FILE")]
+        socket: PathBuf,
+        /// The command to run, like show-message
+        #[clap(required = true)]
+        method: String,
+        /// Notmuch search terms, @name for a saved search
+        search: Vec<String>,
+    },
+    /// Print a completion script for a shell
+    Completions {
+        /// The shell to complete for
+        #[clap(value_parser)]
+        shell: Shell,
+    },
+    /// Print a manpage, in roff
+    Man,
 }
 
-fn from_str(s: &str) -> Sort {
+fn from_str(s: &str) -> Result<Sort> {
         match s {
-            "oldest" => Sort::OldestFirst,
-            "newest" => Sort::NewestFirst,
-            "message-id" => Sort::MessageID,
-            "unsorted" => Sort::Unsorted,
-            _ => panic!("Bad sort option")
+            "oldest" => Ok(Sort::OldestFirst),
+            "newest" => Ok(Sort::NewestFirst),
+            "message-id" => Ok(Sort::MessageID),
+            "unsorted" => Ok(Sort::Unsorted),
+            _ => Err(Error::InvalidSort(s.to_string()))
         }
 }
 
-fn main() -> Result<()>{
-    let args = Cli::parse();
-
-    let db = notmuch::Database::open_with_config(args.db_path, notmuch::DatabaseMode::ReadOnly, args.conf_path, args.profile.as_deref())?;
-    let sort = from_str(&args.sort);
-    let mut writer = std::io::BufWriter::new(io::stdout().lock());
-    let regex = Regex::new(REGEXSTR).unwrap();
-    let templ = Templ {
-        regex,
-        templ_message: &args.entry_fmt,
-        templ_respons: &args.response_fmt,
+// Turn the command line into a request for the daemon. For messages-before
+// and messages-after the first search term is the message id.
+fn client_request(args: &Cli, method: &str, search: &[String]) -> Result<serde_json::Value> {
+    let (mid, search) = match method {
+        "messages-before" | "messages-after" if !search.is_empty() => (Some(&search[0]), &search[1..]),
+        _ => (None, search),
     };
-    let highlight: Option<Highlight> = empty(args.highlight.
-        map(|x| serde_json::from_str(x.as_ref()).expect("Parsing json highlighting failed")));
+    let highlight: Option<serde_json::Value> = args.highlight.as_ref()
+        .map(|x| serde_json::from_str(x)).transpose().map_err(Error::InvalidHighlight)?;
+    Ok(serde_json::json!({
+        "id": 0,
+        "method": method,
+        "params": {
+            "search": search.join(" "),
+            "mid": mid,
+            "limit": args.limit,
+            "offset": args.offset,
+            "highlight": highlight,
+        },
+    }))
+}
 
-    let now = Utc::now();
-    let humanize_range = now - Duration::days(args.humanize_limit);
+impl Commands {
+    // The name of the command, the same as the method in serve mode
+    fn method(&self) -> &'static str {
+        match self {
+            Commands::Messages{..} => "messages",
+            Commands::Threads{..} => "threads",
+            Commands::MessagesBefore{..} => "messages-before",
+            Commands::MessagesAfter{..} => "messages-after",
+            Commands::ShowTree{..} => "show-tree",
+            Commands::ShowSingleTree{..} => "show-single-tree",
+            Commands::ShowMessage{..} => "show-message",
+            Commands::ShowThread{..} => "show-thread",
+            Commands::Count{..} => "count",
+            Commands::ListQueries => "list-queries",
+            Commands::Serve => "serve",
+            Commands::Daemon{..} => "daemon",
+            Commands::Client{..} => "client",
+            Commands::Completions{..} => "completions",
+            Commands::Man => "man",
+        }
+    }
 
-    let runtime = Runtime {
-        db,
-        templ,
-        sort,
-        highlight,
-        humanize_range,
-        date_format: args.date_format,
-        limit: args.limit,
-        offset: args.offset,
-    };
+    fn query(&self) -> String {
+        match self {
+            Commands::Messages{search} |
+            Commands::Threads{search} |
+            Commands::MessagesBefore{search, ..} |
+            Commands::MessagesAfter{search, ..} |
+            Commands::ShowTree{search} |
+            Commands::ShowSingleTree{search} |
+            Commands::ShowMessage{search} |
+            Commands::ShowThread{search} |
+            Commands::Count{search} |
+            Commands::Client{search, ..} => search.join(" "),
+            Commands::ListQueries | Commands::Serve | Commands::Daemon{..} |
+            Commands::Completions{..} | Commands::Man => String::new(),
+        }
+    }
+}
 
-    match &args.command {
-        Commands::Messages{search} => runtime.messages(&search.join(" "), &mut writer)?,
-        Commands::Threads{search} => runtime.threads(&search.join(" "), &mut writer)?,
-        Commands::ShowTree{search} => runtime.show_thread_tree(&search.join(" "), &mut writer)?,
-        Commands::ShowSingleTree{search} => runtime.show_thread_single(&search.join(" "), &mut writer)?,
-        Commands::ShowMessage{search} => runtime.show_messages(&search.join(" "), &mut writer)?,
-        Commands::ShowThread{search} => runtime.show_threads(&search.join(" "), &mut writer)?,
+fn run<W>(runtime: &Runtime, command: &Commands, writer: &mut W) -> Result<()>
+where W: io::Write {
+    match command {
+        Commands::Messages{search} => runtime.messages(&search.join(" "), writer)?,
+        Commands::Threads{search} => runtime.threads(&search.join(" "), writer)?,
+        Commands::ShowTree{search} => runtime.show_thread_tree(&search.join(" "), writer)?,
+        Commands::ShowSingleTree{search} => runtime.show_thread_single(&search.join(" "), writer)?,
+        Commands::ShowMessage{search} => runtime.show_messages(&search.join(" "), writer)?,
+        Commands::ShowThread{search} => runtime.show_threads(&search.join(" "), writer)?,
         Commands::MessagesBefore{id, search} => {
             if search.is_empty() {
-                runtime.show_before_message(id, None, &mut writer)?;
+                runtime.show_before_message(id, None, writer)?;
                 return Ok(())
             }
             let args = search.join(" ");
-            runtime.show_before_message(id, Some(&args), &mut writer)?
+            runtime.show_before_message(id, Some(&args), writer)?
         },
         Commands::MessagesAfter{id, search} => {
             if search.is_empty() {
-                runtime.show_after_message(id, None, &mut writer)?;
+                runtime.show_after_message(id, None, writer)?;
                 return Ok(())
             }
             let args = search.join(" ");
-            runtime.show_after_message(id, Some(&args), &mut writer)?
+            runtime.show_after_message(id, Some(&args), writer)?
         },
+        Commands::Count{search} => runtime.counts(&search.join(" "), writer)?,
+        Commands::ListQueries => runtime.list_queries(writer)?,
+        Commands::Serve | Commands::Daemon{..} | Commands::Client{..} |
+        Commands::Completions{..} | Commands::Man => unreachable!(),
     }
     Ok(())
 }
 
+// The flag if it was given, then the config, then the default of the flag
+fn pick<'a, T: ?Sized>(matches: &ArgMatches, id: &str, flag: &'a T, config: Option<&'a T>) -> &'a T {
+    match (matches.value_source(id), config) {
+        (Some(ValueSource::CommandLine), _) | (_, None) => flag,
+        (_, Some(config)) => config,
+    }
+}
+
+fn start<W>(args: &Cli, matches: &ArgMatches, writer: &mut W) -> Result<()>
+where W: io::Write {
+    let start = Instant::now();
+    match &args.command {
+        Commands::Client{socket, method, search} => {
+            let request = client_request(args, method, search)?;
+            return client(socket, &request, writer)
+        },
+        Commands::Completions{shell} => {
+            generate(*shell, &mut Cli::command(), env!("CARGO_PKG_NAME"), writer);
+            return Ok(())
+        },
+        Commands::Man => {
+            clap_mangen::Man::new(Cli::command()).render(writer)?;
+            return Ok(())
+        },
+        _ => {},
+    }
+
+    let source = Source {
+        db_path: args.db_path.clone(),
+        conf_path: args.conf_path.clone(),
+        profile: args.profile.clone(),
+    };
+    let db = source.open()?;
+    let user = User::load(&db)?;
+    let mut config = Config::load(args.config.as_deref())?;
+    let queries = Queries::load(&db, std::mem::take(&mut config.queries))?;
+    let style = config.style(args.style.as_deref())?;
+    let sort = from_str(pick(matches, "sort", args.sort.as_str(), style.sort.as_deref()))?;
+    let templ = Templ::new(
+        pick(matches, "entry-fmt", args.entry_fmt.as_str(), style.entry_fmt.as_deref()),
+        pick(matches, "response-fmt", args.response_fmt.as_str(), style.response_fmt.as_deref()),
+    )?;
+    let markup: Markup = args.markup.parse()?;
+    let highlight: Option<Highlight> = empty(args.highlight.as_ref().
+        map(|x| serde_json::from_str(x.as_ref())).transpose().map_err(Error::InvalidHighlight)?
+        .or(style.highlight));
+    let date_format = pick(matches, "date-format", args.date_format.as_str(), style.date_format.as_deref());
+    check_format(date_format)?;
+    let humanize_limit = *pick(matches, "humanize-limit", &args.humanize_limit, style.humanize_limit.as_ref());
+
+    let now = Utc::now();
+    let humanize_range = now - Duration::days(humanize_limit);
+
+    let mut runtime = Runtime {
+        db,
+        templ,
+        sort,
+        highlight,
+        humanize_range,
+        date_format: date_format.to_string(),
+        limit: args.limit,
+        offset: args.offset,
+        cancel: None,
+        user,
+        markup,
+        queries,
+    };
+    runtime.prepare_highlight()?;
+
+    match &args.command {
+        Commands::Serve => serve(&mut runtime, io::BufReader::new(io::stdin()), writer)?,
+        Commands::Daemon{socket} => return daemon(runtime, &source, socket),
+        command if args.framed => {
+            let mut counter = Counter::new(&mut *writer);
+            run(&runtime, command, &mut counter)?;
+            let emitted = counter.lines;
+            let summary = Summary::new(&runtime, command.method(), &command.query(), emitted, start)?;
+            summary.write(writer)?;
+        },
+        command => run(&runtime, command, writer)?,
+    }
+    Ok(())
+}
+
+fn main() -> Result<()>{
+    let matches = Cli::command().get_matches();
+    let args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let mut writer = std::io::BufWriter::new(io::stdout().lock());
+
+    match start(&args, &matches, &mut writer) {
+        Err(e) if args.framed => ErrorRecord::new(&e).write(&mut writer),
+        Err(e) if args.json_errors => {
+            writer.flush()?;
+            ErrorRecord::new(&e).write(&mut io::stderr())?;
+            process::exit(1)
+        },
+        result => result,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::process::Command;
@@ -262,16 +459,15 @@ mod tests {
         notmuch::Database::open_with_config(db_path, notmuch::DatabaseMode::ReadOnly, config_path, profile).expect("Couldn't open db")
     }
     
-    fn mock_runtime<'a>(db: Database) -> Runtime<'a> {
-        let regex = Regex::new(REGEXSTR).unwrap();
+    fn mock_runtime(db: Database) -> Runtime {
         let sort = Sort::OldestFirst;
-        let templ = Templ {
-            regex,
-            templ_message: "{date} [{index:02}/{total:02}] {from:25}│ {subject} ({tags})",
-            templ_respons: "{date} [{index:02}/{total:02}] {from:25}│ {response}▶ ({tags})",
-        };
+        let templ = Templ::new(
+            "{date} [{index:02}/{total:02}] {from:25}│ {subject} ({tags})",
+            "{date} [{index:02}/{total:02}] {from:25}│ {response}▶ ({tags})",
+        ).expect("Couldn't parse templates");
         let highlight: Option<Highlight> = None;
         let humanize_range = Utc::now() - Duration::days(5);
+        let user = User::load(&db).expect("Couldn't load user config");
 
         let runtime = Runtime {
             db,
@@ -280,11 +476,25 @@ mod tests {
             highlight,
             humanize_range,
             date_format: "%Y-%m-%d".to_string(),
+            limit: None,
+            offset: None,
+            cancel: None,
+            user,
+            markup: Markup::Plain,
+            queries: Queries::default(),
         };
 
         runtime
     }
 
+    #[test]
+    fn flags_over_config() {
+        let matches = Cli::command().get_matches_from(["nm-livesearch", "--sort", "oldest", "messages", "tag:inbox"]);
+        assert_eq!(pick(&matches, "sort", "oldest", Some("newest")), "oldest");
+        assert_eq!(pick(&matches, "date-format", DATE_FORMAT, Some("%d/%m")), "%d/%m");
+        assert_eq!(pick(&matches, "date-format", DATE_FORMAT, None), DATE_FORMAT);
+    }
+
     #[test]
     fn messages_num() {
         let num = nm_runner("messages", TESTSEARCH);
@@ -316,6 +526,36 @@ mod tests {
         assert_eq!(num, linecounter.lines);
     }
 
+    #[test]
+    fn thread_offset() {
+        let num = nm_runner("threads", TESTSEARCH);
+        let mut linecounter = LineCount::new();
+        let db = open_db();
+        let mut rt = mock_runtime(db);
+        rt.offset = Some(1);
+        rt.threads(TESTSEARCH, &mut linecounter).expect("nm-live: Couldn't fetch messages");
+        assert_eq!(num.saturating_sub(1), linecounter.lines);
+    }
+
+    #[test]
+    fn framed_skipped() {
+        let threads = nm_runner("threads", TESTSEARCH);
+        let db = open_db();
+        let mut rt = mock_runtime(db);
+        rt.offset = Some(1);
+        let summary = |rt: &Runtime, method| {
+            let mut out = Vec::new();
+            Summary::new(rt, method, TESTSEARCH, 0, Instant::now()).unwrap().write(&mut out).unwrap();
+            let record: serde_json::Value = serde_json::from_slice(&out).expect("nm-live: Couldn't parse summary");
+            record["summary"].clone()
+        };
+        assert_eq!(summary(&rt, "threads")["total"], threads);
+        assert_eq!(summary(&rt, "threads")["skipped"], threads.min(1));
+        assert_eq!(summary(&rt, "show-thread")["skipped"], 0);
+        assert_eq!(summary(&rt, "show-single-tree")["skipped"], 0);
+        assert_eq!(summary(&rt, "messages-before")["total"], serde_json::Value::Null);
+    }
+
     #[test]
     fn show_thread_num() {
         let db = open_db();
@@ -326,4 +566,29 @@ mod tests {
         rt.show_threads(TESTSEARCH, &mut linecounter2).expect("nm-live: Couldn't show messages");
         assert_eq!(linecounter.lines, linecounter2.lines);
     }
+
+    #[test]
+    fn count_num() {
+        let messages = nm_runner("messages", TESTSEARCH);
+        let threads = nm_runner("threads", TESTSEARCH);
+        let db = open_db();
+        let rt = mock_runtime(db);
+        let mut out = Vec::new();
+        rt.counts(TESTSEARCH, &mut out).expect("nm-live: Couldn't count");
+        let counts: serde_json::Value = serde_json::from_slice(&out).expect("nm-live: Couldn't parse counts");
+        assert_eq!(counts["messages"], messages);
+        assert_eq!(counts["threads"], threads);
+        // every message matching tag:important has the tag
+        assert_eq!(counts["tags"]["important"], messages);
+        for (tag, count) in counts["tags"].as_object().expect("nm-live: No tags") {
+            let tagged = nm_runner("messages", &format!("'({}) and tag:\"{}\"'", TESTSEARCH, tag));
+            assert_eq!(*count, tagged);
+        }
+
+        let mut out = Vec::new();
+        rt.counts("", &mut out).expect("nm-live: Couldn't count");
+        let counts: serde_json::Value = serde_json::from_slice(&out).expect("nm-live: Couldn't parse counts");
+        assert_eq!(counts["query"], "*");
+        assert_eq!(counts["messages"], nm_runner("messages", "'*'"));
+    }
 }
@@ -0,0 +1,139 @@
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::{Result, Error, template::Rendered};
+
+/// How the style groups of a template, `{from|style=author}`, are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Markup {
+    // ignore the styles
+    Plain,
+    // ANSI colour escapes in the entry, for a terminal
+    Ansi,
+    // a plain entry and a list of byte ranges with their group, for an editor
+    Ranges,
+}
+
+impl FromStr for Markup {
+    type Err = Error;
+
+    fn from_str(str: &str) -> Result<Markup> {
+        match str {
+            "plain" => Ok(Markup::Plain),
+            "ansi" => Ok(Markup::Ansi),
+            "ranges" => Ok(Markup::Ranges),
+            _ => Err(Error::InvalidMarkup(str.to_string())),
+        }
+    }
+}
+
+/// A part of an entry in a style group, start and end are byte offsets
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub start: usize,
+    pub end: usize,
+    pub group: String,
+}
+
+// The colours of the groups, a group can also be a colour name
+fn ansi_code(group: &str) -> Option<&'static str> {
+    let code = match group {
+        "date" => "34",
+        "author" => "33",
+        "subject" => "1",
+        "tags" => "36",
+        "count" => "32",
+        "tree" | "dim" => "2",
+        "bold" => "1",
+        "italic" => "3",
+        "underline" => "4",
+        "black" => "30",
+        "red" => "31",
+        "green" => "32",
+        "yellow" => "33",
+        "blue" => "34",
+        "magenta" => "35",
+        "cyan" => "36",
+        "white" => "37",
+        _ => return None,
+    };
+    Some(code)
+}
+
+// The escapes ansi puts into an entry, with the byte offset they go in front of
+fn escapes(rendered: &Rendered) -> Vec<(usize, String)> {
+    let mut escapes = Vec::new();
+    for span in rendered.spans.iter().filter(|span| span.start < span.end) {
+        if let Some(code) = span.group.and_then(ansi_code) {
+            escapes.push((span.start, format!("\x1b[{}m", code)));
+            escapes.push((span.end, "\x1b[0m".to_string()));
+        }
+    }
+    escapes
+}
+
+impl Markup {
+    /// The entry to print and the styled ranges in it, the ranges are only
+    /// returned for Markup::Ranges
+    pub fn apply(self, rendered: Rendered) -> (String, Vec<Group>) {
+        match self {
+            Markup::Plain => (rendered.text, Vec::new()),
+            Markup::Ranges => {
+                let groups = rendered.spans.iter()
+                    .filter(|span| span.start < span.end)
+                    .filter_map(|span| span.group.map(|group| Group { start: span.start, end: span.end, group: group.to_string() }))
+                    .collect();
+                (rendered.text, groups)
+            },
+            Markup::Ansi => {
+                let mut text = String::with_capacity(rendered.text.len());
+                let mut last = 0;
+                for (at, escape) in escapes(&rendered) {
+                    text.push_str(&rendered.text[last..at]);
+                    text.push_str(&escape);
+                    last = at;
+                }
+                text.push_str(&rendered.text[last..]);
+                (text, Vec::new())
+            },
+        }
+    }
+
+    /// Where a range of the rendered entry is in the entry from apply. It starts
+    /// after the escapes at its start and ends before the ones at its end.
+    pub fn shift(self, rendered: &Rendered, start: usize, end: usize) -> (usize, usize) {
+        if self != Markup::Ansi {
+            return (start, end)
+        }
+        let escapes = escapes(rendered);
+        let before = |offset: usize, at_offset: bool| -> usize {
+            escapes.iter()
+                .filter(|(at, _)| *at < offset || (at_offset && *at == offset))
+                .map(|(_, escape)| escape.len())
+                .sum()
+        };
+        (start + before(start, true), end + before(end, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::{Template, mock::Mock};
+
+    #[test]
+    fn markup() {
+        let templ = Template::parse("{from|style=author}: {subject|style=nope}").unwrap();
+        let (text, styles) = Markup::Ansi.apply(templ.render_spans(&Mock).unwrap());
+        assert_eq!(text, "\x1b[33mApa\x1b[0m: Hello");
+        assert!(styles.is_empty());
+        let (text, styles) = Markup::Ranges.apply(templ.render_spans(&Mock).unwrap());
+        assert_eq!(text, "Apa: Hello");
+        assert_eq!(styles, vec![
+            Group { start: 0, end: 3, group: "author".to_string() },
+            Group { start: 5, end: 10, group: "nope".to_string() },
+        ]);
+        assert_eq!("html".parse::<Markup>().unwrap_err().code(), "invalid-markup");
+    }
+}
@@ -1,18 +1,18 @@
-use std::{io, path::PathBuf, borrow::Cow};
+use std::{io, path::PathBuf};
 
 use serde::{Serialize, ser::SerializeStruct};
 
-use crate::Result;
+use crate::{Result, sanitize::sanitize, user::User};
 
-pub struct Message(pub notmuch::Message, pub i32, pub i32);
+pub struct Message<'a>(pub notmuch::Message, pub i32, pub i32, pub &'a User);
 
 // TODO currently we are just using Subject and id
-impl Serialize for Message {
+impl<'a> Serialize for Message<'a> {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer {
             use serde::ser::Error;
-            let mut mes = serializer.serialize_struct("Message", 7)?;
+            let mut mes = serializer.serialize_struct("Message", 13)?;
             let id = self.0.id();
             mes.serialize_field("id", &id)?;
             let date = self.0.date();
@@ -23,9 +23,12 @@ impl Serialize for Message {
             mes.serialize_field("filenames", &filenames)?;
             let tags: Vec<String> = self.0.tags().collect();
             mes.serialize_field("tags", &tags)?;
-            let from = self.0.header("From").map_err(Error::custom)?.unwrap_or_else(|| Cow::from(""));
+            let from = self.0.header("From").map_err(Error::custom)?.unwrap_or_default();
+            let from_me = self.3.sent(&from);
+            let from = sanitize(&from);
             mes.serialize_field("from", &from)?;
-            let subject = self.0.header("Subject").map_err(Error::custom)?.unwrap_or_else(|| Cow::from(""));
+            mes.serialize_field("from_me", &from_me)?;
+            let subject = sanitize(&self.0.header("Subject").map_err(Error::custom)?.unwrap_or_default());
             mes.serialize_field("subject", &subject)?;
             let tid = self.0.thread_id();
             mes.serialize_field("tid", &tid)?;
@@ -39,7 +42,7 @@ impl Serialize for Message {
     }
 }
 
-impl Message {
+impl<'a> Message<'a> {
     pub fn show_message<W>(&self, writer: &mut W) -> Result<()> 
         where W: io::Write {
             serde_json::to_writer(&mut *writer, self)?;
@@ -0,0 +1,119 @@
+use std::convert::TryFrom;
+
+use regex::Regex;
+use serde::Deserialize;
+
+/// How a string criterion is compared to the value of the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Exact,
+    Substring,
+    CaseInsensitive,
+    Regex,
+    Glob,
+}
+
+/// A string criterion of a highlight. Either a plain string, compared with
+/// the default mode of the criterion, or an object naming the mode, like
+/// `{"regex": "^\\[PATCH"}` or `{"glob": "*@github.com"}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "Spec")]
+pub struct Pattern {
+    mode: Option<Mode>,
+    text: String,
+    // compiled when the highlight is parsed, for regex and glob
+    regex: Option<Regex>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Spec {
+    Plain(String),
+    Mode(ModeSpec),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ModeSpec {
+    Exact(String),
+    Substring(String),
+    CaseInsensitive(String),
+    Regex(String),
+    Glob(String),
+}
+
+// "*@github.com" -> "^.*@github\.com$"
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+impl TryFrom<Spec> for Pattern {
+    type Error = String;
+
+    fn try_from(spec: Spec) -> Result<Pattern, String> {
+        let compile = |text: &str, regex: &str| Regex::new(regex).map(Some).map_err(|e| format!("bad pattern {:?}: {}", text, e));
+        let pattern = match spec {
+            Spec::Plain(text) => Pattern { mode: None, text, regex: None },
+            Spec::Mode(ModeSpec::Exact(text)) => Pattern { mode: Some(Mode::Exact), text, regex: None },
+            Spec::Mode(ModeSpec::Substring(text)) => Pattern { mode: Some(Mode::Substring), text, regex: None },
+            Spec::Mode(ModeSpec::CaseInsensitive(text)) => Pattern { mode: Some(Mode::CaseInsensitive), text: text.to_lowercase(), regex: None },
+            Spec::Mode(ModeSpec::Regex(text)) => Pattern { mode: Some(Mode::Regex), regex: compile(&text, &text)?, text },
+            Spec::Mode(ModeSpec::Glob(text)) => Pattern { mode: Some(Mode::Glob), regex: compile(&text, &glob_to_regex(&text))?, text },
+        };
+        Ok(pattern)
+    }
+}
+
+impl Pattern {
+    /// Compare with the mode of the pattern, or `default` if it's a plain string
+    pub fn matches(&self, value: &str, default: Mode) -> bool {
+        match self.mode.unwrap_or(default) {
+            Mode::Exact => value == self.text,
+            Mode::Substring => value.contains(&self.text),
+            Mode::CaseInsensitive => value.to_lowercase().contains(&self.text),
+            // a plain string can't default to regex or glob, so regex is set
+            Mode::Regex | Mode::Glob => self.regex.as_ref().is_some_and(|regex| regex.is_match(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlight::Highlight;
+
+    fn pattern(json: &str) -> Pattern {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn modes() {
+        assert!(pattern(r#""apa""#).matches("apa", Mode::Exact));
+        assert!(!pattern(r#""apa""#).matches("Mr apa", Mode::Exact));
+        assert!(pattern(r#""apa""#).matches("Mr apa", Mode::Substring));
+        assert!(pattern(r#"{"exact": "apa"}"#).matches("apa", Mode::Substring));
+        assert!(!pattern(r#"{"exact": "apa"}"#).matches("Mr apa", Mode::Substring));
+        assert!(pattern(r#"{"case-insensitive": "APA"}"#).matches("Mr Apa", Mode::Exact));
+        assert!(pattern(r#"{"regex": "^\\[PATCH"}"#).matches("[PATCH 1/2] Fix", Mode::Exact));
+        assert!(!pattern(r#"{"regex": "^\\[PATCH"}"#).matches("Re: [PATCH 1/2] Fix", Mode::Exact));
+        assert!(pattern(r#"{"glob": "*@github.com"}"#).matches("noreply@github.com", Mode::Exact));
+        assert!(!pattern(r#"{"glob": "*@github.com"}"#).matches("noreply@githubXcom", Mode::Exact));
+        assert!(serde_json::from_str::<Pattern>(r#"{"regex": "("}"#).is_err());
+        assert!(serde_json::from_str::<Pattern>(r#"{"fuzzy": "apa"}"#).is_err());
+    }
+
+    #[test]
+    fn bad_regex() {
+        let error = serde_json::from_str::<Highlight>(r#"{"subject": {"regex": "^[PATCH"}}"#).unwrap_err();
+        assert!(error.to_string().contains(r#"bad pattern "^[PATCH""#), "{}", error);
+    }
+}
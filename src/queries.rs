@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashMap};
+
+use notmuch::Database;
+use serde::Serialize;
+
+use crate::Result;
+
+// How deep saved searches can use other saved searches, so a loop ends
+const MAX_DEPTH: usize = 8;
+
+/// A named search, like inbox = "tag:inbox and not tag:killed"
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Saved {
+    pub name: String,
+    pub query: String,
+    // "config" or "notmuch"
+    pub source: &'static str,
+}
+
+/// The saved searches from our config and the [query] section of the notmuch config
+#[derive(Debug, Clone, Default)]
+pub struct Queries {
+    queries: BTreeMap<String, Saved>,
+}
+
+impl Queries {
+    /// Ours win over the ones in the notmuch config
+    pub fn load(db: &Database, config: HashMap<String, String>) -> Result<Queries> {
+        let mut queries = Queries::default();
+        for (key, query) in db.config_list("query.")? {
+            if let Some(name) = key.strip_prefix("query.") {
+                queries.insert(name, query, "notmuch");
+            }
+        }
+        for (name, query) in config {
+            queries.insert(&name, query, "config");
+        }
+        Ok(queries)
+    }
+
+    /// Read the notmuch config of db again, keeping ours
+    pub fn reload(&self, db: &Database) -> Result<Queries> {
+        let ours = self.iter()
+            .filter(|saved| saved.source == "config")
+            .map(|saved| (saved.name.clone(), saved.query.clone()))
+            .collect();
+        Queries::load(db, ours)
+    }
+
+    fn insert(&mut self, name: &str, query: String, source: &'static str) {
+        let saved = Saved { name: name.to_string(), query, source };
+        self.queries.insert(name.to_string(), saved);
+    }
+
+    /// All of them, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = &Saved> {
+        self.queries.values()
+    }
+
+    // The saved search named at the start of str, @name or query:name, and how long the reference is
+    fn reference(&self, str: &str) -> Option<(&Saved, usize)> {
+        let prefix = if str.starts_with('@') {
+            1
+        } else if str.starts_with("query:") {
+            6
+        } else {
+            return None
+        };
+        let rest = &str[prefix..];
+        let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        self.queries.get(&rest[..len]).map(|saved| (saved, prefix + len))
+    }
+
+    /// Replace @name and query:name with the saved search, in parentheses.
+    /// Names we don't know, and anything in quotes, are left for notmuch.
+    pub fn expand(&self, search: &str) -> String {
+        self.expand_depth(search, 0)
+    }
+
+    fn expand_depth(&self, search: &str, depth: usize) -> String {
+        if depth >= MAX_DEPTH || self.queries.is_empty() {
+            return search.to_string();
+        }
+        let mut expanded = String::with_capacity(search.len());
+        let mut quoted = false;
+        let mut prev: Option<char> = None;
+        let mut i = 0;
+        while let Some(c) = search[i..].chars().next() {
+            let start = !quoted && prev.is_none_or(|p| p.is_whitespace() || p == '(');
+            if let Some((saved, len)) = self.reference(&search[i..]).filter(|_| start) {
+                expanded.push('(');
+                expanded.push_str(&self.expand_depth(&saved.query, depth + 1));
+                expanded.push(')');
+                prev = Some(')');
+                i += len;
+                continue;
+            }
+            if c == '"' {
+                quoted = !quoted;
+            }
+            expanded.push(c);
+            prev = Some(c);
+            i += c.len_utf8();
+        }
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queries() -> Queries {
+        let mut queries = Queries::default();
+        queries.insert("inbox", "tag:inbox and not tag:killed".to_string(), "config");
+        queries.insert("boss", "from:boss@bep.com".to_string(), "notmuch");
+        queries.insert("todo", "@inbox and tag:todo".to_string(), "config");
+        queries.insert("loop", "@loop".to_string(), "config");
+        queries
+    }
+
+    #[test]
+    fn expand() {
+        let queries = queries();
+        assert_eq!(queries.expand("@inbox and from:bob"), "(tag:inbox and not tag:killed) and from:bob");
+        assert_eq!(queries.expand("query:boss or (@inbox)"), "(from:boss@bep.com) or ((tag:inbox and not tag:killed))");
+        assert_eq!(queries.expand("@todo"), "((tag:inbox and not tag:killed) and tag:todo)");
+        assert_eq!(queries.expand("@nope and query:nope"), "@nope and query:nope");
+        assert_eq!(queries.expand("from:apa@inbox subject:\"@inbox\""), "from:apa@inbox subject:\"@inbox\"");
+        assert_eq!(queries.expand("@loop"), format!("{}@loop{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH)));
+        let names: Vec<_> = queries.iter().map(|saved| saved.name.as_str()).collect();
+        assert_eq!(names, vec!["boss", "inbox", "loop", "todo"]);
+    }
+}
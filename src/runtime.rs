@@ -1,28 +1,40 @@
-use std::{io, collections::BinaryHeap};
+use std::{io, fs, path::Path, collections::{BinaryHeap, BTreeMap}};
 
-use chrono::{DateTime, Utc, NaiveDateTime};
+use chrono::{DateTime, Utc};
 use chrono_humanize::HumanTime;
 use notmuch::{Database, Sort, Messages};
-use regex::{Regex, Captures};
 use serde::Serialize;
 
-use crate::{Result, message::Message, highlight::Highlight, thread::Thread, time::{compare_time, compare_diff, show_time}, ordered::{flush_messages, OrderMessage}};
+use crate::{Result, Error, address::Address, sanitize::sanitize, user::User, cancel::Cancel, markup::{Markup, Group}, queries::Queries, message::Message, highlight::{Highlight, Match}, thread::Thread, time::{compare_time, compare_diff, show_time, to_datetime}, ordered::{flush_messages, OrderMessage}, template::{Field, Fields, Rendered, Template, Value}};
 
-pub struct Runtime<'a> {
+pub struct Runtime {
     pub db: Database,
-    pub templ: Templ<'a>,
+    pub templ: Templ,
     pub sort: Sort,
     pub highlight: Option<Highlight>,
     pub date_format: String,
     pub humanize_range: DateTime<Utc>,
     pub limit: Option<usize>,
     pub offset: Option<usize>,
+    pub cancel: Option<Cancel>,
+    pub user: User,
+    pub markup: Markup,
+    pub queries: Queries,
 }
 
-pub struct Templ<'a> {
-    pub regex: Regex,
-    pub templ_message: &'a str,
-    pub templ_respons: &'a str,
+#[derive(Clone)]
+pub struct Templ {
+    pub templ_message: Template,
+    pub templ_respons: Template,
+}
+
+impl Templ {
+    /// Parse the templates once, so a bad template is an error before we start printing
+    pub fn new(templ_message: &str, templ_respons: &str) -> Result<Templ> {
+        let templ_message = Template::parse(templ_message)?;
+        let templ_respons = Template::parse(templ_respons)?;
+        Ok(Templ { templ_message, templ_respons })
+    }
 }
 
 
@@ -31,41 +43,165 @@ pub struct Show {
     id: String,
     entry: String,
     highlight: bool,
+    // what matched the highlight, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    highlighted: Option<Match>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    styles: Vec<Group>,
+}
+
+#[derive(Serialize, Debug)]
+struct Counts<'a> {
+    query: &'a str,
+    messages: u32,
+    threads: u32,
+    // messages per tag, tags without any are left out
+    tags: BTreeMap<String, u32>,
+}
+
+#[derive(Serialize, Debug)]
+struct Listed<'a> {
+    name: &'a str,
+    query: &'a str,
+    source: &'static str,
+    messages: u32,
+    threads: u32,
 }
 
-fn fix_subject(sub: &str) -> String {
-    sub.chars()
-        .map(|x| match x { 
-            '\r' => ' ', 
-            '\n' => ' ',
-            _ => x
-        }).collect()
+// The maildir folder of a file, "work/INBOX" for <db>/work/INBOX/cur/<file>
+fn folder(db_path: &Path, filename: &Path) -> String {
+    let dir = filename.parent().unwrap_or(filename);
+    let dir = match dir.file_name().and_then(|x| x.to_str()) {
+        Some("cur") | Some("new") | Some("tmp") => dir.parent().unwrap_or(dir),
+        _ => dir,
+    };
+    dir.strip_prefix(db_path).unwrap_or(dir).display().to_string()
 }
 
-fn show_thread<W>(thread: &notmuch::Thread, writer: &mut W) -> Result<()> 
+fn human_size(size: u64) -> String {
+    match size {
+        0..=1023 => format!("{}B", size),
+        1024..=1048575 => format!("{:.1}K", size as f64 / 1024.0),
+        _ => format!("{:.1}M", size as f64 / 1048576.0),
+    }
+}
+
+// notmuch separates the authors of matched messages from the rest with a |
+fn split_authors(thread: &notmuch::Thread) -> (String, String) {
+    let authors = sanitize(&thread.authors().join(", "));
+    match authors.split_once('|') {
+        Some((matched, unmatched)) => (matched.trim().to_string(), unmatched.trim().to_string()),
+        None => (authors, String::new()),
+    }
+}
+
+// The authors of a thread, each parsed and shown with show
+fn map_authors<F>(thread: &notmuch::Thread, show: F) -> String
+where F: Fn(&Address) -> String {
+    let authors: Vec<String> = thread.authors().iter()
+        .flat_map(|author| author.split('|'))
+        .map(|author| show(&Address::parse(author)))
+        .filter(|author| !author.is_empty())
+        .collect();
+    authors.join(", ")
+}
+
+fn show_thread<W>(thread: &notmuch::Thread, highlighted: Option<Match>, writer: &mut W) -> Result<()> 
     where W: io::Write {
-    let ser = Thread(&thread);
+    let ser = Thread(thread, highlighted);
     serde_json::to_writer(&mut *writer, &ser)?;
     write!(writer, "\n")?;
     Ok(())
 }
 
-impl<'a> Runtime<'a> {
-    fn humanize(&self, date: i64, pad: usize) -> String {
-        let naive = NaiveDateTime::from_timestamp_opt(date, 0).expect("Couldn't humanize string");
-        let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
+impl Runtime {
+    /// Evaluate the queries in the highlight, before we start printing
+    pub fn prepare_highlight(&mut self) -> Result<()> {
+        match self.highlight {
+            Some(ref mut hl) => hl.prepare(&self.db, &self.queries),
+            None => Ok(()),
+        }
+    }
+
+    // Called between messages and threads so a newer query can stop us
+    fn check_cancel(&self) -> Result<()> {
+        match self.cancel {
+            Some(ref cancel) if cancel.cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    fn humanize(&self, date: i64) -> Result<String> {
+        let datetime = to_datetime(date)?;
 
         if datetime > self.humanize_range {
             let ht = HumanTime::from(datetime);
-            format!("{:pad$}", ht)
+            Ok(ht.to_string())
         } else {
-            format!("{:pad$}", datetime.format(&self.date_format))
+            Ok(datetime.format(&self.date_format).to_string())
+        }
+    }
+
+    // A query with the saved searches in it expanded
+    fn query(&self, search: &str) -> Result<notmuch::Query<'_>> {
+        Ok(self.db.create_query(&self.queries.expand(search))?)
+    }
+
+    pub fn count(&self, search: &str, threads: bool) -> Result<u32> {
+        let query = self.query(search)?;
+        if threads {
+            Ok(query.count_threads()?)
+        } else {
+            Ok(query.count_messages()?)
+        }
+    }
+
+    /// How many messages and threads match, and how many messages have each tag
+    pub fn counts<W>(&self, search: &str, writer: &mut W) -> Result<()>
+    where W: io::Write {
+        // "" would match everything, but not in "() and tag:x"
+        let search = if search.trim().is_empty() { "*" } else { search };
+        let mut tags = BTreeMap::new();
+        for tag in self.db.all_tags()? {
+            self.check_cancel()?;
+            // through count, so a saved search in the search is expanded too
+            let count = self.count(&format!("({}) and tag:\"{}\"", search, tag.replace('"', "\"\"")), false)?;
+            if count > 0 {
+                tags.insert(tag, count);
+            }
+        }
+        let counts = Counts {
+            query: search,
+            messages: self.count(search, false)?,
+            threads: self.count(search, true)?,
+            tags,
+        };
+        serde_json::to_writer(&mut *writer, &counts)?;
+        writeln!(writer)?;
+        Ok(())
+    }
+
+    /// The saved searches and how many messages and threads they match
+    pub fn list_queries<W>(&self, writer: &mut W) -> Result<()>
+    where W: io::Write {
+        for saved in self.queries.iter() {
+            self.check_cancel()?;
+            let listed = Listed {
+                name: &saved.name,
+                query: &saved.query,
+                source: saved.source,
+                messages: self.count(&saved.query, false)?,
+                threads: self.count(&saved.query, true)?,
+            };
+            serde_json::to_writer(&mut *writer, &listed)?;
+            writeln!(writer)?;
         }
+        Ok(())
     }
 
     pub fn messages<W>(&self, str: &str, writer: &mut W) -> Result<()>
         where W: io::Write {
-            let query = self.db.create_query(str)?;
+            let query = self.query(str)?;
             query.set_sort(self.sort);
             let messages = query.search_messages()?;
             let skip = self.offset.unwrap_or(0);
@@ -74,12 +210,14 @@ impl<'a> Runtime<'a> {
                     if num >= limit {
                         break;
                     }
-                    let mes = Message(message, 1, 1);
+                    self.check_cancel()?;
+                    let mes = Message(message, 1, 1, &self.user);
                     mes.show_message(writer)?;
                 }
             } else {
                 for message in messages.skip(skip) {
-                    let mes = Message(message, 1, 1);
+                    self.check_cancel()?;
+                    let mes = Message(message, 1, 1, &self.user);
                     mes.show_message(writer)?;
                 }
             }
@@ -88,7 +226,7 @@ impl<'a> Runtime<'a> {
 
     pub fn threads<W>(&self, str: &str, writer: &mut W) -> Result<()>
         where W: io::Write {
-            let query = self.db.create_query(str)?;
+            let query = self.query(str)?;
             query.set_sort(self.sort);
             let threads = query.search_threads()?;
             let skip = self.offset.unwrap_or(0);
@@ -97,11 +235,15 @@ impl<'a> Runtime<'a> {
                     if num >= limit {
                         break;
                     }
-                    show_thread(&thread, writer)?;
+                    self.check_cancel()?;
+                    let highlight = self.highlight.as_ref().map_or(Ok(None), |hl| hl.thread(&thread, &self.user))?;
+                    show_thread(&thread, highlight, writer)?;
                 }
             } else {
-                for thread in threads {
-                    show_thread(&thread, writer)?;
+                for thread in threads.skip(skip) {
+                    self.check_cancel()?;
+                    let highlight = self.highlight.as_ref().map_or(Ok(None), |hl| hl.thread(&thread, &self.user))?;
+                    show_thread(&thread, highlight, writer)?;
                 }
             }
             Ok(())
@@ -109,7 +251,7 @@ impl<'a> Runtime<'a> {
 
     pub fn show_thread_tree<W>(&self, search: &str, writer: &mut W) -> Result<()>
         where W: io::Write {
-            let query = self.db.create_query(search)?;
+            let query = self.query(search)?;
             query.set_sort(self.sort);
             let threads = query.search_threads()?;
             let skip = self.offset.unwrap_or(0);
@@ -119,6 +261,7 @@ impl<'a> Runtime<'a> {
                     if num >= limit {
                         break;
                     }
+                    self.check_cancel()?;
                     let total = thread.total_messages();
                     let messages = thread.toplevel_messages();
                     let mut vec = Vec::new();
@@ -129,6 +272,7 @@ impl<'a> Runtime<'a> {
                 }
             } else {
                 for thread in threads.skip(skip) {
+                    self.check_cancel()?;
                     let total = thread.total_messages();
                     let messages = thread.toplevel_messages();
                     let mut vec = Vec::new();
@@ -175,14 +319,14 @@ impl<'a> Runtime<'a> {
             // TODO: Can we even get the numbering correctly?
             // Maybe a hashmap with id -> num
             if level > 0 && n > 0 {
-                let highlight = self.highlight.as_ref().map_or(Ok(false), |hl| hl.message(message, n+1, total))?;
-                let str = self.template_message(&self.templ.templ_respons, &message, Some(newstring), n+1, total)?;
-                let show = Show { id: id.to_string(), entry: str, highlight};
+                let highlight = self.highlight.as_ref().map_or(Ok(None), |hl| hl.message(message, n+1, total, &self.user))?;
+                let rendered = self.template_message(&self.templ.templ_respons, &message, Some(newstring), n+1, total)?;
+                let show = self.show(&id, rendered, highlight);
                 vec.push(show)
             } else {
-                let highlight = self.highlight.as_ref().map_or(Ok(false), |hl| hl.message(message, n+1, total))?;
-                let str = self.template_message(&self.templ.templ_message, &message, None, n+1, total)?;
-                let show = Show { id: id.to_string(), entry: str, highlight};
+                let highlight = self.highlight.as_ref().map_or(Ok(None), |hl| hl.message(message, n+1, total, &self.user))?;
+                let rendered = self.template_message(&self.templ.templ_message, &message, None, n+1, total)?;
+                let show = self.show(&id, rendered, highlight);
                 vec.push(show)
             }
 
@@ -225,18 +369,18 @@ impl<'a> Runtime<'a> {
 
             if level > 0 && n > 0 {
                 if matched {
-                    let highlight = self.highlight.as_ref().map_or(Ok(false), |hl| hl.message(message, n+1, total))?;
-                    let str = self.template_message(&self.templ.templ_respons, &message, Some(newstring), n+1, total)?;
-                    let show = Show { id: id.to_string(), entry: str, highlight };
+                    let highlight = self.highlight.as_ref().map_or(Ok(None), |hl| hl.message(message, n+1, total, &self.user))?;
+                    let rendered = self.template_message(&self.templ.templ_respons, &message, Some(newstring), n+1, total)?;
+                    let show = self.show(&id, rendered, highlight);
                     serde_json::to_writer(&mut *writer, &show)?;
                     write!(writer,"\n")?;
                     return Ok(-1);
                 }
             } else {
                 if matched {
-                    let highlight = self.highlight.as_ref().map_or(Ok(false), |hl| hl.message(message, n+1, total))?;
-                    let str = self.template_message(&self.templ.templ_message, &message, None, n+1, total)?;
-                    let show = Show { id: id.to_string(), entry: str, highlight};
+                    let highlight = self.highlight.as_ref().map_or(Ok(None), |hl| hl.message(message, n+1, total, &self.user))?;
+                    let rendered = self.template_message(&self.templ.templ_message, &message, None, n+1, total)?;
+                    let show = self.show(&id, rendered, highlight);
                     serde_json::to_writer(&mut *writer, &show)?;
                     write!(writer,"\n")?;
                     return Ok(-1);
@@ -260,10 +404,11 @@ impl<'a> Runtime<'a> {
 
     pub fn show_thread_single<W>(&self, search: &str, writer: &mut W) -> Result<()>
     where W: io::Write {
-        let query = self.db.create_query(search)?;
+        let query = self.query(search)?;
         query.set_sort(self.sort);
         let threads = query.search_threads()?;
         for thread in threads {
+            self.check_cancel()?;
             let total = thread.total_messages();
             let messages = thread.toplevel_messages();
             let mvec: Vec<notmuch::Message> = messages.collect();
@@ -274,13 +419,14 @@ impl<'a> Runtime<'a> {
 
     pub fn show_messages<W>(&self, search: &str, writer: &mut W) -> Result<()>
     where W: io::Write {
-        let query = self.db.create_query(&search)?;
+        let query = self.query(&search)?;
         query.set_sort(self.sort);
         let threads = query.search_threads()?;
         let mut heap = BinaryHeap::new();
         let mut skip = self.offset;
         let mut limit = self.limit;
         for thread in threads {
+            self.check_cancel()?;
             let reference = compare_time(&thread, self.sort);
             let total = thread.total_messages();
             if flush_messages(&mut heap, self.sort, reference, &mut skip, &mut limit, writer)? {
@@ -289,6 +435,7 @@ impl<'a> Runtime<'a> {
             let top = thread.messages();
             let mut counter = 0;
             for message in top {
+                self.check_cancel()?;
                 counter = self.show_messages_helper(&message, reference, counter, total, writer, &mut heap)?;
             }
         }
@@ -304,9 +451,9 @@ impl<'a> Runtime<'a> {
             if matched {
                 let id = message.id();
                 let unix_date = message.date();
-                let highlight = self.highlight.as_ref().map_or(Ok(false), |hl| hl.message(message, counter, total))?;
-                let str = self.template_message(&self.templ.templ_message, &message, None, counter, total)?;
-                let show = Show { id: id.to_string(), entry: str, highlight};
+                let highlight = self.highlight.as_ref().map_or(Ok(None), |hl| hl.message(message, counter, total, &self.user))?;
+                let rendered = self.template_message(&self.templ.templ_message, &message, None, counter, total)?;
+                let show = self.show(&id, rendered, highlight);
                 if compare_diff(unix_date, reference, self.sort) {
                     let om = OrderMessage(unix_date, self.sort, show);
                     heap.push(om)
@@ -320,13 +467,15 @@ impl<'a> Runtime<'a> {
 
     pub fn show_threads<W>(&self, search: &str, writer: &mut W) -> Result<()>
     where W: io::Write {
-        let query = self.db.create_query(&search)?;
+        let query = self.query(&search)?;
         query.set_sort(self.sort);
         let threads = query.search_threads()?;
         for thread in threads {
+            self.check_cancel()?;
             let id = thread.id();
-            let str = self.template_thread(&self.templ.templ_message, &thread)?;
-            let tuple = Show { id: id.to_string(), entry: str, highlight: false };
+            let highlight = self.highlight.as_ref().map_or(Ok(None), |hl| hl.thread(&thread, &self.user))?;
+            let rendered = self.template_thread(&self.templ.templ_message, &thread)?;
+            let tuple = self.show(&id, rendered, highlight);
             serde_json::to_writer(&mut *writer, &tuple)?;
             write!(writer,"\n")?;
         }
@@ -340,7 +489,7 @@ impl<'a> Runtime<'a> {
             query.push_str(" and ");
             query.push_str(str);
         }
-        let q = self.db.create_query(&query)?;
+        let q = self.query(&query)?;
         let mut threads = q.search_threads()?;
         if let Some(thread) = threads.next() {
             let messages = thread.toplevel_messages();
@@ -348,7 +497,7 @@ impl<'a> Runtime<'a> {
 
             if let Some(bef) = bef {
                 for message in bef {
-                    let mes = Message(message, 1, 1);
+                    let mes = Message(message, 1, 1, &self.user);
                     mes.show_message(writer)?;
                 }
             }
@@ -385,13 +534,13 @@ impl<'a> Runtime<'a> {
         None
     }
 
-    fn print_tree<W>(messages: Messages, writer: &mut W) -> Result<()>
+    fn print_tree<W>(&self, messages: Messages, writer: &mut W) -> Result<()>
     where W: io::Write {
         for message in messages {
             let rep = message.replies();
-            let mes = Message(message, 1, 1);
+            let mes = Message(message, 1, 1, &self.user);
             mes.show_message(writer)?;
-            Self::print_tree(rep, writer)?;
+            self.print_tree(rep, writer)?;
         }
         Ok(())
     }
@@ -403,7 +552,7 @@ impl<'a> Runtime<'a> {
             query.push_str(" and ");
             query.push_str(str);
         }
-        let q = self.db.create_query(&query)?;
+        let q = self.query(&query)?;
         let mut threads = q.search_threads()?;
         if let Some(thread) = threads.next() {
             let messages = thread.toplevel_messages();
@@ -411,78 +560,150 @@ impl<'a> Runtime<'a> {
 
             if let Some(after) = after {
                 let reps = after.replies();
-                Self::print_tree(reps, writer)?;
+                self.print_tree(reps, writer)?;
             }
         }
         Ok(())
     }
 
-    // TODO: Merge things that are in common
-    fn template_message(&self, template: &str, message: &notmuch::Message, response: Option<String>, num: i32, total: i32) -> Result<String> {
-        let from = message.header("From")?.unwrap_or_default();
-
-        let tags: Vec<String> = message.tags().collect();
-        let tags_string = tags.join(", ");
-        let unix_date = message.date();
-        let date = show_time(unix_date, &self.date_format);
-
-        let subject = message.header("Subject")?.unwrap_or_default();
-        let subfixed = fix_subject(&subject);
-
-        let template = self.templ.regex.replace_all(template, |caps: &Captures| {
-            let str = caps.get(1).map(|x| x.as_str().trim());
-            let pad: usize = caps.get(2).map(|x| x.as_str().parse().expect("Couldn't parse padding")).unwrap_or(0);
-
-            let string = match str {
-                Some("Date") => self.humanize(unix_date, pad),
-                Some("date") => format!("{:pad$}", date),
-                Some("index") => format!("{:0>pad$}", num),
-                Some("total") => format!("{:0>pad$}", total),
-                Some("from") => format!("{:pad$}", from),
-                Some("subject") => format!("{:pad$}", subfixed),
-                Some("response") => {
-                    match response {
-                        Some(ref response) => format!("{:pad$}", response),
-                        None => panic!("Trying to use response outside of tree")
-                    }
-                },
-                Some("tags") => format!("{:pad$}", tags_string),
-                Some(x) => panic!("Tag {} not supported", x),
-                _ => panic!("Syntax error, couldn't match template")
-            };
-            string
-        });
-        Ok(template.to_string())
+    fn template_message<'t>(&self, template: &'t Template, message: &notmuch::Message, response: Option<String>, num: i32, total: i32) -> Result<Rendered<'t>> {
+        let fields = MessageFields { runtime: self, message, response, num, total };
+        template.render_spans(&fields)
     }
 
-    fn template_thread(&self, template: &str, thread: &notmuch::Thread) -> Result<String> {
-        let subject = thread.subject();
-        let subfixed = fix_subject(&subject);
-        let authors = thread.authors().join(", ");
-        let total = thread.total_messages();
-        let matched = thread.matched_messages();
-        let unix_date = thread.newest_date();
-        let date = show_time(unix_date, &self.date_format);
-        let tags: Vec<String> = thread.tags().collect();
-        let tags_string = tags.join(", ");
-
-        let template = self.templ.regex.replace_all(template, |caps: &Captures| {
-            let str = caps.get(1).map(|x| x.as_str().trim());
-            let pad: usize = caps.get(2).map(|x| x.as_str().parse().unwrap()).unwrap_or(0);
-
-            let string = match str {
-                Some("Date") => self.humanize(unix_date, pad),
-                Some("date") => format!("{:pad$}", date),
-                Some("index") => format!("{:0>pad$}", matched),
-                Some("total") => format!("{:0>pad$}", total),
-                Some("from") => format!("{:pad$}", authors),
-                Some("subject") => format!("{:pad$}", subfixed),
-                Some("tags") => format!("{:pad$}", tags_string),
-                Some(x) => panic!("Tag {} not supported", x),
-                _ => panic!("Syntax error, couldn't match template")
-            };
-            string
-        });
-        Ok(template.to_string())
+    fn template_thread<'t>(&self, template: &'t Template, thread: &notmuch::Thread) -> Result<Rendered<'t>> {
+        let fields = ThreadFields { runtime: self, thread };
+        template.render_spans(&fields)
+    }
+
+    fn show(&self, id: &str, rendered: Rendered, mut highlighted: Option<Match>) -> Show {
+        if let Some(ref mut matched) = highlighted {
+            matched.locate(&rendered, self.markup);
+        }
+        let (entry, styles) = self.markup.apply(rendered);
+        Show { id: id.to_string(), entry, highlight: highlighted.is_some(), highlighted, styles }
+    }
+}
+
+// The fields of a message, only looked up when the template uses them
+struct MessageFields<'a> {
+    runtime: &'a Runtime,
+    message: &'a notmuch::Message,
+    response: Option<String>,
+    num: i32,
+    total: i32,
+}
+
+impl<'a> MessageFields<'a> {
+    // Headers can contain anything, make sure they are safe to print
+    fn header(&self, name: &str) -> Result<Value> {
+        Ok(Value::Text(sanitize(&self.message.header(name)?.unwrap_or_default())))
+    }
+
+    fn from(&self) -> Result<Option<Address>> {
+        let from = self.message.header("From")?.unwrap_or_default();
+        Ok(Address::parse_list(&from).into_iter().next())
+    }
+}
+
+impl<'a> Fields for MessageFields<'a> {
+    fn value(&self, field: &Field) -> Result<Value> {
+        let message = self.message;
+        match field {
+            Field::Humanized | Field::OldestHumanized => Ok(Value::Text(self.runtime.humanize(message.date())?)),
+            Field::Date | Field::Oldest => Ok(Value::Text(show_time(message.date(), &self.runtime.date_format)?.to_string())),
+            Field::Index => Ok(Value::Number(self.num.into())),
+            Field::Total => Ok(Value::Number(self.total.into())),
+            Field::From => self.header("From"),
+            Field::Subject => self.header("Subject"),
+            // Empty outside of a tree, so {response|subject} works everywhere
+            Field::Response => Ok(Value::Text(self.response.clone().unwrap_or_default())),
+            Field::Tags => {
+                let tags: Vec<String> = message.tags().collect();
+                Ok(Value::Text(tags.join(", ")))
+            },
+            Field::To => self.header("To"),
+            Field::Cc => self.header("Cc"),
+            Field::ReplyTo => self.header("Reply-To"),
+            Field::ListId => self.header("List-Id"),
+            Field::MessageId => Ok(Value::Text(message.id().to_string())),
+            Field::ThreadId => Ok(Value::Text(message.thread_id().to_string())),
+            Field::Filename => Ok(Value::Text(message.filename().display().to_string())),
+            Field::Folder => Ok(Value::Text(folder(self.runtime.db.path(), &message.filename()))),
+            Field::Size => {
+                let size = fs::metadata(message.filename()).map(|m| human_size(m.len()));
+                Ok(Value::Text(size.unwrap_or_default()))
+            },
+            Field::Attachment => Ok(Value::flag(message.tags().any(|tag| tag == "attachment"))),
+            Field::Matched => Ok(Value::flag(message.get_flag(notmuch::MessageFlag::Match))),
+            Field::Excluded => Ok(Value::flag(message.get_flag(notmuch::MessageFlag::Excluded))),
+            // A message only has one author
+            Field::AuthorsMatched => self.header("From"),
+            Field::AuthorsUnmatched => Ok(Value::Text(String::new())),
+            // A missing header is just empty
+            Field::Header(name) => self.header(name),
+            Field::FromName => Ok(Value::Text(self.from()?.and_then(|x| x.name).unwrap_or_default())),
+            Field::FromAddr => Ok(Value::Text(self.from()?.map(|x| x.addr).unwrap_or_default())),
+            Field::FromShort => Ok(Value::Text(self.from()?.map(|x| x.short()).unwrap_or_default())),
+            // Like notmuch-emacs, show who we sent it to if it's from us
+            Field::FromOrTo => {
+                match self.from()? {
+                    Some(from) if self.runtime.user.is_me(&from.addr) => {
+                        let to = message.header("To")?.unwrap_or_default();
+                        let to: Vec<String> = Address::parse_list(&to).iter().map(Address::short).collect();
+                        Ok(Value::Text(format!("To: {}", to.join(", "))))
+                    },
+                    from => Ok(Value::Text(from.map(|x| x.short()).unwrap_or_default())),
+                }
+            },
+        }
+    }
+}
+
+struct ThreadFields<'a> {
+    runtime: &'a Runtime,
+    thread: &'a notmuch::Thread,
+}
+
+impl<'a> ThreadFields<'a> {
+    // A field of the first message in the thread
+    fn first(&self, field: &Field) -> Result<Value> {
+        match self.thread.toplevel_messages().next() {
+            Some(message) => {
+                let fields = MessageFields { runtime: self.runtime, message: &message, response: None, num: 1, total: self.thread.total_messages() };
+                fields.value(field)
+            },
+            None => Ok(Value::Text(String::new())),
+        }
+    }
+}
+
+impl<'a> Fields for ThreadFields<'a> {
+    fn value(&self, field: &Field) -> Result<Value> {
+        let thread = self.thread;
+        match field {
+            Field::Humanized => Ok(Value::Text(self.runtime.humanize(thread.newest_date())?)),
+            Field::Date => Ok(Value::Text(show_time(thread.newest_date(), &self.runtime.date_format)?.to_string())),
+            Field::OldestHumanized => Ok(Value::Text(self.runtime.humanize(thread.oldest_date())?)),
+            Field::Oldest => Ok(Value::Text(show_time(thread.oldest_date(), &self.runtime.date_format)?.to_string())),
+            Field::Index | Field::Matched => Ok(Value::Number(thread.matched_messages().into())),
+            Field::Total => Ok(Value::Number(thread.total_messages().into())),
+            Field::From => Ok(Value::Text(sanitize(&thread.authors().join(", ")))),
+            Field::AuthorsMatched => Ok(Value::Text(split_authors(thread).0)),
+            Field::AuthorsUnmatched => Ok(Value::Text(split_authors(thread).1)),
+            Field::FromName => Ok(Value::Text(map_authors(thread, |x| x.name.clone().unwrap_or_else(|| x.addr.clone())))),
+            Field::FromShort | Field::FromOrTo => Ok(Value::Text(map_authors(thread, Address::short))),
+            // notmuch only keeps the names of the authors
+            Field::FromAddr => self.first(field),
+            Field::Subject => Ok(Value::Text(sanitize(&thread.subject()))),
+            Field::Tags => {
+                let tags: Vec<String> = thread.tags().collect();
+                Ok(Value::Text(tags.join(", ")))
+            },
+            Field::ThreadId => Ok(Value::Text(thread.id().to_string())),
+            Field::Response => Ok(Value::Text(String::new())),
+            // Everything else comes from the first message in the thread
+            _ => self.first(field),
+        }
     }
 }
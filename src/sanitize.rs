@@ -0,0 +1,47 @@
+/// Decode RFC 2047 encoded words, "=?utf-8?q?Apa?=". If the header
+/// isn't valid we keep it as it is.
+pub fn decode(str: &str) -> String {
+    if !str.contains("=?") {
+        return str.to_string()
+    }
+    rfc2047_decoder::decode(str).unwrap_or_else(|_| str.to_string())
+}
+
+// Characters that change the direction of the text that follows,
+// they can make a line look like something it isn't.
+fn is_bidi(c: char) -> bool {
+    matches!(c, '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Make a header value safe to print on a single line: decode encoded words,
+/// drop control and bidi characters and collapse all whitespace to a single space.
+pub fn sanitize(str: &str) -> String {
+    let decoded = decode(str);
+    let mut out = String::with_capacity(decoded.len());
+    let mut space = false;
+    for c in decoded.chars() {
+        if c.is_whitespace() {
+            space = true;
+        } else if !c.is_control() && !is_bidi(c) {
+            if space && !out.is_empty() {
+                out.push(' ');
+            }
+            space = false;
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_headers() {
+        assert_eq!(sanitize("  Re:\tHello\r\n  world "), "Re: Hello world");
+        assert_eq!(sanitize("evil\u{202E}gnp.exe\u{1b}[31m"), "evilgnp.exe[31m");
+        assert_eq!(sanitize("=?utf-8?q?Hej_d=C3=A5?="), "Hej då");
+        assert_eq!(decode("=?broken"), "=?broken");
+    }
+}
@@ -0,0 +1,218 @@
+use std::{io, sync::mpsc, thread, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, Error, cancel::{Cancel, Generations}, frame::{Counter, Summary, ErrorRecord}, highlight::Highlight, runtime::Runtime};
+
+// A request is a single line of json:
+// {"id": 1, "method": "messages", "params": {"search": "tag:inbox", "limit": 20}}
+// Every new request cancels the ones still running, {"id": 2, "method": "cancel"}
+// just cancels.
+#[derive(Deserialize, Debug)]
+pub struct Request {
+    pub id: serde_json::Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Params,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Params {
+    #[serde(default)]
+    pub search: String,
+    // message id used by messages-before and messages-after
+    pub mid: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub highlight: Option<Highlight>,
+    // end with a summary of the results
+    #[serde(default)]
+    pub framed: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct Response<'a> {
+    id: &'a serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorRecord>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cancelled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<Summary>,
+    done: bool,
+}
+
+// Wraps every line written by the runtime into {"id": .., "result": ..}
+// so a client can tell which request a line belongs to.
+struct Tagged<'a, W: io::Write> {
+    id: String,
+    line: Vec<u8>,
+    writer: &'a mut W,
+}
+
+impl<'a, W: io::Write> io::Write for Tagged<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for c in buf {
+            if *c == b'\n' {
+                write!(self.writer, "{{\"id\":{},\"result\":", self.id)?;
+                self.writer.write_all(&self.line)?;
+                self.writer.write_all(b"}\n")?;
+                self.line.clear();
+            } else {
+                self.line.push(*c);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub(crate) fn respond<W>(id: &serde_json::Value, result: Result<Option<Summary>>, writer: &mut W) -> Result<()>
+where W: io::Write {
+    let (summary, error, cancelled) = match result {
+        Ok(summary) => (summary, None, false),
+        Err(Error::Cancelled) => (None, None, true),
+        Err(e) => (None, Some(ErrorRecord::new(&e)), false),
+    };
+    let resp = Response { id, error, cancelled, summary, done: true };
+    serde_json::to_writer(&mut *writer, &resp)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn dispatch<W>(runtime: &Runtime, method: &str, params: &Params, writer: &mut W) -> Result<()>
+where W: io::Write {
+    if method == "cancel" {
+        return Ok(())
+    }
+    let search = params.search.as_str();
+    let filter = if search.is_empty() { None } else { Some(search) };
+    match (method, &params.mid) {
+        ("messages", _) => runtime.messages(search, writer)?,
+        ("threads", _) => runtime.threads(search, writer)?,
+        ("show-tree", _) => runtime.show_thread_tree(search, writer)?,
+        ("show-single-tree", _) => runtime.show_thread_single(search, writer)?,
+        ("show-message", _) => runtime.show_messages(search, writer)?,
+        ("show-thread", _) => runtime.show_threads(search, writer)?,
+        ("list-queries", _) => runtime.list_queries(writer)?,
+        ("count", _) => runtime.counts(search, writer)?,
+        ("messages-before", Some(mid)) => runtime.show_before_message(mid, filter, writer)?,
+        ("messages-after", Some(mid)) => runtime.show_after_message(mid, filter, writer)?,
+        _ => return Err(Error::UnknownMethod(method.to_string())),
+    }
+    Ok(())
+}
+
+// Reads and parses requests, handing each one a new generation
+// so it cancels the request currently running.
+fn read_requests<R, F>(input: R, generations: &Generations, mut send: F) -> Result<()>
+where
+    R: io::BufRead,
+    F: FnMut(Cancel, serde_json::Result<Request>) -> bool,
+{
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = serde_json::from_str(&line);
+        if !send(generations.next(), request) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// The settings from the command line, used when a request doesn't override them.
+// The highlight stays in the runtime, it's prepared once.
+struct Defaults {
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+impl Defaults {
+    fn new(runtime: &Runtime) -> Defaults {
+        Defaults {
+            limit: runtime.limit,
+            offset: runtime.offset,
+        }
+    }
+}
+
+fn answer<W>(runtime: &mut Runtime, defaults: &Defaults, cancel: Cancel, request: serde_json::Result<Request>, writer: &mut W) -> Result<()>
+where W: io::Write {
+    let Request { id, method, mut params } = match request {
+        Ok(request) => request,
+        Err(e) => return respond(&serde_json::Value::Null, Err(e.into()), writer),
+    };
+
+    runtime.limit = params.limit.or(defaults.limit);
+    runtime.offset = params.offset.or(defaults.offset);
+    // A request with its own highlight has it prepared, and the default put back after
+    let default = params.highlight.take().map(|highlight| runtime.highlight.replace(highlight));
+    runtime.cancel = Some(cancel);
+
+    let start = Instant::now();
+    let mut tagged = Tagged {
+        id: serde_json::to_string(&id)?,
+        line: Vec::new(),
+        writer: &mut *writer,
+    };
+    let mut counter = Counter::new(&mut tagged);
+    let prepared = if default.is_some() { runtime.prepare_highlight() } else { Ok(()) };
+    let result = prepared
+        .and_then(|_| dispatch(runtime, &method, &params, &mut counter))
+        .and_then(|_| if params.framed {
+            Summary::new(runtime, &method, &params.search, counter.lines, start).map(Some)
+        } else {
+            Ok(None)
+        });
+    runtime.cancel = None;
+    if let Some(default) = default {
+        runtime.highlight = default;
+    }
+    respond(&id, result, writer)
+}
+
+/// Read requests from input, one json per line, and answer them
+/// using the same runtime until the input is closed.
+pub fn serve<R, W>(runtime: &mut Runtime, input: R, writer: &mut W) -> Result<()>
+where
+    R: io::BufRead + Send + 'static,
+    W: io::Write,
+{
+    serve_with(runtime, input, writer, |_| Ok(()))
+}
+
+// Like serve, calling refresh before answering a request. If it fails
+// the request is answered with its error.
+pub(crate) fn serve_with<R, W, F>(runtime: &mut Runtime, input: R, writer: &mut W, mut refresh: F) -> Result<()>
+where
+    R: io::BufRead + Send + 'static,
+    W: io::Write,
+    F: FnMut(&mut Runtime) -> Result<()>,
+{
+    let generations = Generations::new();
+    let (sender, receiver) = mpsc::channel();
+    let defaults = Defaults::new(runtime);
+
+    // Requests are read on their own thread, so a new request
+    // can cancel the one we are answering. It isn't joined on an error,
+    // it would wait for the next line of input.
+    let reader = thread::spawn(move || {
+        read_requests(input, &generations, move |cancel, request| sender.send((cancel, request)).is_ok())
+    });
+    for (cancel, request) in receiver {
+        if let Err(e) = refresh(runtime) {
+            let id = request.map_or(serde_json::Value::Null, |request| request.id);
+            respond(&id, Err(e), writer)?;
+            continue;
+        }
+        answer(runtime, &defaults, cancel, request, writer)?;
+    }
+    reader.join().expect("Request reader panicked")
+}
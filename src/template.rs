@@ -0,0 +1,481 @@
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::{Result, Error};
+
+/// The fields that can be used in a template, like {from} or {index:02}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    // Date, humanized if it's recent
+    Humanized,
+    Date,
+    Index,
+    Total,
+    From,
+    Subject,
+    Response,
+    Tags,
+    To,
+    Cc,
+    ReplyTo,
+    ListId,
+    MessageId,
+    ThreadId,
+    Filename,
+    // The maildir folder, relative to the database
+    Folder,
+    Size,
+    Attachment,
+    Matched,
+    Excluded,
+    // Threads only
+    AuthorsMatched,
+    AuthorsUnmatched,
+    OldestHumanized,
+    Oldest,
+    // Any header, {header:X-Mailer}
+    Header(String),
+    // The name, address and name or local part of From
+    FromName,
+    FromAddr,
+    FromShort,
+    // "To: bob" if the message is from us
+    FromOrTo,
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Field> {
+        match name {
+            "Date" => Ok(Field::Humanized),
+            "date" => Ok(Field::Date),
+            "index" => Ok(Field::Index),
+            "total" => Ok(Field::Total),
+            "from" => Ok(Field::From),
+            "subject" => Ok(Field::Subject),
+            "response" => Ok(Field::Response),
+            "tags" => Ok(Field::Tags),
+            "to" => Ok(Field::To),
+            "cc" => Ok(Field::Cc),
+            "reply_to" => Ok(Field::ReplyTo),
+            "list_id" => Ok(Field::ListId),
+            "message_id" => Ok(Field::MessageId),
+            "thread_id" => Ok(Field::ThreadId),
+            "filename" => Ok(Field::Filename),
+            "folder" => Ok(Field::Folder),
+            "size" => Ok(Field::Size),
+            "attachment" => Ok(Field::Attachment),
+            "matched" => Ok(Field::Matched),
+            "excluded" => Ok(Field::Excluded),
+            "authors_matched" => Ok(Field::AuthorsMatched),
+            "authors_unmatched" => Ok(Field::AuthorsUnmatched),
+            "Oldest" => Ok(Field::OldestHumanized),
+            "oldest" => Ok(Field::Oldest),
+            "from_name" => Ok(Field::FromName),
+            "from_addr" => Ok(Field::FromAddr),
+            "from_short" => Ok(Field::FromShort),
+            "from_or_to" => Ok(Field::FromOrTo),
+            x => Err(Error::TemplateError(format!("Tag {} not supported", x))),
+        }
+    }
+}
+
+/// The value of a field. Numbers are padded with zeros, text with spaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Text(String),
+    Number(i64),
+}
+
+impl Value {
+    /// Flags are shown as a * when set
+    pub fn flag(set: bool) -> Value {
+        Value::Text(if set { "*" } else { "" }.to_string())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::Text(str) => str.is_empty(),
+            Value::Number(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+/// How to fit a value into a column, {from:25}, {from:<25.25}, {subject:.60}, {index:>3}.
+/// Widths are in terminal columns, so wide characters count as two.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Format {
+    pub align: Option<Align>,
+    pub width: usize,
+    // longer values are cut and end with …
+    pub max: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Literal(String),
+    // The first of the fields that isn't empty
+    Field { fields: Vec<Field>, format: Format, group: Option<String> },
+    // The body is only shown if one of the fields isn't empty
+    Section { fields: Vec<Field>, body: Template },
+}
+
+/// Looks up the value of a field when a template is rendered
+pub trait Fields {
+    fn value(&self, field: &Field) -> Result<Value>;
+}
+
+// The value of the first field that isn't empty, or an empty value
+fn first_value<F: Fields>(fields: &F, alternatives: &[Field]) -> Result<Value> {
+    for field in alternatives {
+        let value = fields.value(field)?;
+        if !value.is_empty() {
+            return Ok(value)
+        }
+    }
+    Ok(Value::Text(String::new()))
+}
+
+/// Where a field ended up in the rendered text, in bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span<'t> {
+    pub start: usize,
+    pub end: usize,
+    // the fields of the node, the first one that wasn't empty was used
+    pub fields: &'t [Field],
+    // the style group, from |style=group
+    pub group: Option<&'t str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Rendered<'t> {
+    pub text: String,
+    pub spans: Vec<Span<'t>>,
+}
+
+/// A template parsed once, when we start, and rendered for every message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+// name or header:Name, followed by an optional format
+fn parse_spec(spec: &str) -> Result<(Field, Option<Format>)> {
+    let mut parts = spec.split(':').map(|x| x.trim());
+    let field = match parts.next() {
+        Some("header") => match parts.next() {
+            Some(header) if !header.is_empty() => Field::Header(header.to_string()),
+            _ => return Err(Error::TemplateError("header needs a name, like {header:X-Mailer}".to_string())),
+        },
+        Some(name) => Field::parse(name)?,
+        None => unreachable!(),
+    };
+    let format = parts.next().map(Format::parse).transpose()?;
+    if let Some(extra) = parts.next() {
+        return Err(Error::TemplateError(format!("Unexpected {} in {{{}}}", extra, spec)))
+    }
+    Ok((field, format))
+}
+
+// {from}, {from:25}, {header:X-Mailer:20} or {from|to:25}.
+// With alternatives the format goes after the last one.
+// Options, like style=author, go at the very end: {from:25|style=author}
+fn parse_field(inner: &str) -> Result<Node> {
+    let mut alternatives: Vec<&str> = inner.split('|').collect();
+    let mut group = None;
+    while let Some(option) = alternatives.last().and_then(|x| x.split_once('=')) {
+        match (option.0.trim(), option.1.trim()) {
+            ("style", name) if !name.is_empty() => group = Some(name.to_string()),
+            _ => return Err(Error::TemplateError(format!("Unknown option {} in {{{}}}", option.0, inner))),
+        }
+        alternatives.pop();
+    }
+    let mut fields = Vec::new();
+    let mut format = Format::default();
+    for (i, alternative) in alternatives.iter().enumerate() {
+        let (field, spec) = parse_spec(alternative)?;
+        if let Some(spec) = spec {
+            if i + 1 < alternatives.len() {
+                return Err(Error::TemplateError(format!("The format goes after the last field in {{{}}}", inner)))
+            }
+            format = spec;
+        }
+        fields.push(field);
+    }
+    Ok(Node::Field { fields, format, group })
+}
+
+// {?tags: ({tags})} or {?list_id|header:X-List: on a list}
+fn parse_section(inner: &str) -> Result<Node> {
+    // The condition ends at the first : that isn't part of header:Name
+    let mut colons = inner.match_indices(':').map(|(i, _)| i);
+    let split = loop {
+        match colons.next() {
+            Some(i) if inner[..i].rsplit('|').next().map(|x| x.trim()) == Some("header") => continue,
+            Some(i) => break i,
+            None => return Err(Error::TemplateError(format!("A section needs a body, like {{?tags: ({{tags}})}}, not {{?{}}}", inner))),
+        }
+    };
+    let mut fields = Vec::new();
+    for alternative in inner[..split].split('|') {
+        fields.push(parse_spec(alternative)?.0);
+    }
+    let body = Template::parse(&inner[split + 1..])?;
+    Ok(Node::Section { fields, body })
+}
+
+// The index of the } closing an already opened {
+fn closing_brace(str: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in str.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_width(width: &str) -> Result<usize> {
+    width.parse().map_err(|_| Error::TemplateError(format!("Couldn't parse padding {}", width)))
+}
+
+// Cut str to at most max columns, ending with … if we had to cut
+fn truncate(str: &str, max: usize) -> String {
+    if str.width() <= max {
+        return str.to_string()
+    }
+    let mut out = String::new();
+    let mut width = 0;
+    for c in str.chars() {
+        let w = c.width().unwrap_or(0);
+        if width + w + 1 > max {
+            break;
+        }
+        width += w;
+        out.push(c);
+    }
+    if max > 0 {
+        out.push('…');
+    }
+    out
+}
+
+fn pad(str: &str, width: usize, align: Align, fill: char, out: &mut String) {
+    let missing = width.saturating_sub(str.width());
+    let (before, after) = match align {
+        Align::Left => (0, missing),
+        Align::Right => (missing, 0),
+        Align::Center => (missing / 2, missing - missing / 2),
+    };
+    out.extend(std::iter::repeat_n(fill, before));
+    out.push_str(str);
+    out.extend(std::iter::repeat_n(fill, after));
+}
+
+impl Format {
+    // [<>^][width][.max]
+    fn parse(spec: &str) -> Result<Format> {
+        let (align, rest) = match spec.chars().next() {
+            Some('<') => (Some(Align::Left), &spec[1..]),
+            Some('>') => (Some(Align::Right), &spec[1..]),
+            Some('^') => (Some(Align::Center), &spec[1..]),
+            _ => (None, spec),
+        };
+        let (width, max) = match rest.split_once('.') {
+            Some((width, max)) => (width, Some(parse_width(max)?)),
+            None => (rest, None),
+        };
+        let width = if width.is_empty() { 0 } else { parse_width(width)? };
+        Ok(Format { align, width, max })
+    }
+
+    /// Numbers are aligned right and padded with zeros, unless an alignment is given
+    fn write(&self, value: &Value, out: &mut String) {
+        let (str, align, fill) = match (value, self.align) {
+            (Value::Number(n), None) => (n.to_string(), Align::Right, '0'),
+            (Value::Number(n), Some(align)) => (n.to_string(), align, ' '),
+            (Value::Text(str), align) => (str.clone(), align.unwrap_or(Align::Left), ' '),
+        };
+        let str = match self.max {
+            Some(max) => truncate(&str, max),
+            None => str,
+        };
+        pad(&str, self.width, align, fill, out);
+    }
+}
+
+impl Template {
+    /// Parse a template like "{date} {from:25}│ {subject}{?tags: ({tags})}".
+    /// A { without a matching } is just text.
+    pub fn parse(template: &str) -> Result<Template> {
+        let mut nodes = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            let open = &rest[start + 1..];
+            let len = match closing_brace(open) {
+                Some(len) => len,
+                None => {
+                    literal.push('{');
+                    rest = open;
+                    continue;
+                }
+            };
+            if !literal.is_empty() {
+                nodes.push(Node::Literal(std::mem::take(&mut literal)));
+            }
+            let inner = &open[..len];
+            match inner.strip_prefix('?') {
+                Some(section) => nodes.push(parse_section(section)?),
+                None => nodes.push(parse_field(inner)?),
+            }
+            rest = &open[len + 1..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            nodes.push(Node::Literal(literal));
+        }
+        Ok(Template { nodes })
+    }
+
+    pub fn uses(&self, field: &Field) -> bool {
+        self.nodes.iter().any(|node| match node {
+            Node::Literal(_) => false,
+            Node::Field { fields, .. } => fields.contains(field),
+            Node::Section { fields, body } => fields.contains(field) || body.uses(field),
+        })
+    }
+
+    /// Only the fields used in the template are looked up
+    pub fn render<F: Fields>(&self, fields: &F) -> Result<String> {
+        Ok(self.render_spans(fields)?.text)
+    }
+
+    /// Render and remember where every field ended up
+    pub fn render_spans<F: Fields>(&self, fields: &F) -> Result<Rendered<'_>> {
+        let mut out = Rendered::default();
+        self.render_into(fields, &mut out)?;
+        Ok(out)
+    }
+
+    fn render_into<'t, F: Fields>(&'t self, fields: &F, out: &mut Rendered<'t>) -> Result<()> {
+        for node in &self.nodes {
+            match node {
+                Node::Literal(str) => out.text.push_str(str),
+                Node::Field { fields: alternatives, format, group } => {
+                    let start = out.text.len();
+                    format.write(&first_value(fields, alternatives)?, &mut out.text);
+                    out.spans.push(Span { start, end: out.text.len(), fields: alternatives, group: group.as_deref() });
+                },
+                Node::Section { fields: alternatives, body } => {
+                    if !first_value(fields, alternatives)?.is_empty() {
+                        body.render_into(fields, out)?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+// The fields of a message, for the tests of templates and of what uses them
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+
+    pub struct Mock;
+
+    impl Fields for Mock {
+        fn value(&self, field: &Field) -> Result<Value> {
+            match field {
+                Field::Index => Ok(Value::Number(3)),
+                Field::From | Field::FromShort => Ok(Value::Text("Apa".to_string())),
+                Field::To => Ok(Value::Text("日本語の名前".to_string())),
+                Field::Subject => Ok(Value::Text("Hello".to_string())),
+                Field::Matched => Ok(Value::flag(true)),
+                Field::Header(name) if name == "X-Mailer" => Ok(Value::Text("mutt".to_string())),
+                _ => Ok(Value::Text(String::new())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock::Mock;
+
+    #[test]
+    fn parse_and_render() {
+        let templ = Template::parse("[{index:02}] {from:5}│ {subject}{matched:2}").unwrap();
+        assert_eq!(templ.render(&Mock).unwrap(), "[03] Apa  │ Hello* ");
+        assert!(templ.uses(&Field::From));
+        assert!(!templ.uses(&Field::Tags));
+    }
+
+    #[test]
+    fn headers() {
+        let templ = Template::parse("{header:X-Mailer:6}|{header: List-Id }|").unwrap();
+        assert_eq!(templ.render(&Mock).unwrap(), "mutt  ||");
+        assert!(templ.uses(&Field::Header("List-Id".to_string())));
+    }
+
+    #[test]
+    fn alignment_and_truncation() {
+        let templ = Template::parse("{from:>5}|{from:^7}|{subject:.4}|{subject:6.6}|{index:<3}|").unwrap();
+        assert_eq!(templ.render(&Mock).unwrap(), "  Apa|  Apa  |Hel…|Hello |3  |");
+        // wide characters take two columns
+        let templ = Template::parse("{to:8.8}|{to:14}|").unwrap();
+        assert_eq!(templ.render(&Mock).unwrap(), "日本語… |日本語の名前  |");
+    }
+
+    #[test]
+    fn styles() {
+        let templ = Template::parse("{index:02|style=count} {from|to:5|style=author}|").unwrap();
+        let rendered = templ.render_spans(&Mock).unwrap();
+        assert_eq!(rendered.text, "03 Apa  |");
+        let styles: Vec<(usize, usize, Option<&str>)> = rendered.spans.iter().map(|x| (x.start, x.end, x.group)).collect();
+        assert_eq!(styles, vec![(0, 2, Some("count")), (3, 8, Some("author"))]);
+        assert!(matches!(Template::parse("{from|colour=red}"), Err(Error::TemplateError(_))));
+    }
+
+    #[test]
+    fn unmatched_brace_is_text() {
+        let templ = Template::parse("{subject} {oops").unwrap();
+        assert_eq!(templ.render(&Mock).unwrap(), "Hello {oops");
+        let templ = Template::parse("{ {subject}").unwrap();
+        assert_eq!(templ.render(&Mock).unwrap(), "{ Hello");
+    }
+
+    #[test]
+    fn sections_and_fallbacks() {
+        let templ = Template::parse("{subject}{?tags: ({tags})}{?from: <{from}>}").unwrap();
+        assert_eq!(templ.render(&Mock).unwrap(), "Hello <Apa>");
+        let templ = Template::parse("{tags|header:X-Mailer|from:6}|{response|subject}").unwrap();
+        assert_eq!(templ.render(&Mock).unwrap(), "mutt  |Hello");
+        let templ = Template::parse("{?header:X-Mailer:[{header:X-Mailer}]}{?tags|cc:x}").unwrap();
+        assert_eq!(templ.render(&Mock).unwrap(), "[mutt]");
+        assert!(templ.uses(&Field::Tags));
+    }
+
+    #[test]
+    fn bad_templates() {
+        assert!(matches!(Template::parse("{nope}"), Err(Error::TemplateError(_))));
+        assert!(matches!(Template::parse("{from:x}"), Err(Error::TemplateError(_))));
+        assert!(matches!(Template::parse("{header}"), Err(Error::TemplateError(_))));
+        assert!(matches!(Template::parse("{from:2:3}"), Err(Error::TemplateError(_))));
+        assert!(matches!(Template::parse("{from:<.}"), Err(Error::TemplateError(_))));
+        assert!(matches!(Template::parse("{from:5|to}"), Err(Error::TemplateError(_))));
+        assert!(matches!(Template::parse("{?tags}"), Err(Error::TemplateError(_))));
+    }
+}
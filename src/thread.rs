@@ -1,22 +1,28 @@
 use serde::{Serialize, ser::SerializeStruct};
 
-pub struct Thread<'a>(pub &'a notmuch::Thread);
+use crate::{highlight::Match, sanitize::sanitize};
+
+pub struct Thread<'a>(pub &'a notmuch::Thread, pub Option<Match>);
 
 impl<'a> Serialize for Thread<'a> {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer {
-            let mut mes = serializer.serialize_struct("Message", 5)?;
+            let mut mes = serializer.serialize_struct("Message", 7)?;
             let id = self.0.id();
             mes.serialize_field("id", &id)?;
             let date = self.0.newest_date();
             mes.serialize_field("date", &date)?;
             let tags: Vec<String> = self.0.tags().collect();
             mes.serialize_field("tags", &tags)?;
-            let authors = self.0.authors();
+            let authors: Vec<String> = self.0.authors().iter().map(|x| sanitize(x)).collect();
             mes.serialize_field("authors", &authors)?;
-            let subject = self.0.subject();
+            let subject = sanitize(&self.0.subject());
             mes.serialize_field("subject", &subject)?;
+            mes.serialize_field("highlight", &self.1.is_some())?;
+            if let Some(ref highlighted) = self.1 {
+                mes.serialize_field("highlighted", highlighted)?;
+            }
             mes.end()
     }
 }
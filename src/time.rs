@@ -1,10 +1,94 @@
-use chrono::{format::{DelayedFormat, StrftimeItems}, NaiveDateTime, DateTime, Utc};
+use std::convert::TryFrom;
+
+use chrono::{format::{DelayedFormat, Item, StrftimeItems}, Duration, NaiveDate, NaiveDateTime, DateTime, Utc};
 use notmuch::Sort;
+use serde::Deserialize;
+
+use crate::{Result, Error};
+
+pub fn to_datetime(date: i64) -> Result<DateTime<Utc>> {
+    DateTime::from_timestamp(date, 0).ok_or(Error::InvalidDate(date))
+}
+
+/// Check a date format once, chrono panics when it prints a bad one
+pub fn check_format(date_format: &str) -> Result<()> {
+    if StrftimeItems::new(date_format).any(|item| item == Item::Error) {
+        return Err(Error::TemplateError(format!("Bad date format: {}", date_format)))
+    }
+    Ok(())
+}
+
+pub fn show_time(date: i64, date_format: &str) -> Result<DelayedFormat<StrftimeItems<'_>>> {
+    let datetime = to_datetime(date)?;
+    Ok(datetime.format(date_format))
+}
+
+/// A point in time in a highlight: a date like "2021-11-09" or
+/// "2021-11-09 14:00", "today", "yesterday", or how long ago like "6h",
+/// "3d", "2w", "1m" (30 days) or "1y" (365 days). Days start at midnight UTC.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum When {
+    At(i64),
+    Ago(Duration),
+    // the start of a day, days ago
+    Day(i64),
+}
+
+impl When {
+    pub fn parse(str: &str) -> Result<When> {
+        let str = str.trim();
+        let invalid = || Error::InvalidDateSpec(str.to_string());
+        match str {
+            "now" => return Ok(When::Ago(Duration::zero())),
+            "today" => return Ok(When::Day(0)),
+            "yesterday" => return Ok(When::Day(1)),
+            _ => {},
+        }
+        if let Ok(date) = NaiveDate::parse_from_str(str, "%Y-%m-%d") {
+            let datetime = date.and_hms_opt(0, 0, 0).ok_or_else(invalid)?;
+            return Ok(When::At(datetime.and_utc().timestamp()));
+        }
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(str, "%Y-%m-%d %H:%M") {
+            return Ok(When::At(datetime.and_utc().timestamp()));
+        }
+        let unit = str.chars().last().ok_or_else(invalid)?;
+        let count: i64 = str[..str.len() - unit.len_utf8()].parse().map_err(|_| invalid())?;
+        let ago = match unit {
+            'h' => Duration::try_hours(count),
+            'd' => Duration::try_days(count),
+            'w' => Duration::try_weeks(count),
+            'm' => count.checked_mul(30).and_then(Duration::try_days),
+            'y' => count.checked_mul(365).and_then(Duration::try_days),
+            _ => return Err(invalid()),
+        };
+        Ok(When::Ago(ago.ok_or_else(invalid)?))
+    }
+
+    /// The unix time it stands for, relative to now. "today" is midnight UTC,
+    /// not local time, like the dates.
+    pub fn timestamp(&self, now: DateTime<Utc>) -> Result<i64> {
+        match *self {
+            When::At(date) => Ok(date),
+            When::Ago(ago) => now.checked_sub_signed(ago)
+                .map(|date| date.timestamp())
+                .ok_or_else(|| Error::InvalidDate(now.timestamp().saturating_sub(ago.num_seconds()))),
+            When::Day(days) => {
+                let day = now.naive_utc().date().and_hms_opt(0, 0, 0).unwrap_or_else(|| now.naive_utc());
+                Duration::try_days(days).and_then(|days| day.checked_sub_signed(days))
+                    .map(|date| date.and_utc().timestamp())
+                    .ok_or(Error::InvalidDate(day.and_utc().timestamp()))
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for When {
+    type Error = Error;
 
-pub fn show_time<'a>(date: i64, date_format: &'a str) -> DelayedFormat<StrftimeItems<'a>> {
-    let naive = NaiveDateTime::from_timestamp_opt(date, 0).expect("Couldn't parse datetime");
-    let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
-    datetime.format(date_format)
+    fn try_from(str: String) -> Result<When> {
+        When::parse(&str)
+    }
 }
 
 pub fn compare_time(thread: &notmuch::Thread, sort: Sort) -> i64 {
@@ -30,3 +114,40 @@ pub fn compare_diff(current: i64, reference: i64, sort: Sort) -> bool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format() {
+        assert!(check_format("%Y-%m-%d %H:%M").is_ok());
+        assert_eq!(check_format("%Y-%Q").unwrap_err().code(), "template");
+    }
+
+    #[test]
+    fn when() {
+        let now = to_datetime(1636470000).unwrap(); // 2021-11-09 15:00
+        let timestamp = |str| When::parse(str).unwrap().timestamp(now).unwrap();
+        assert_eq!(timestamp("2021-11-09"), 1636416000);
+        assert_eq!(timestamp("2021-11-09 12:00"), 1636459200);
+        assert_eq!(timestamp("today"), 1636416000);
+        assert_eq!(timestamp("yesterday"), 1636416000 - 86400);
+        assert_eq!(timestamp("2w"), 1636470000 - 14 * 86400);
+        assert_eq!(timestamp("6h"), 1636470000 - 6 * 3600);
+        assert_eq!(When::parse("2x").unwrap_err().code(), "invalid-date");
+        assert!(When::parse("").is_err());
+        assert!(When::parse("soon").is_err());
+        assert_eq!(When::parse("99999999999999d").unwrap_err().code(), "invalid-date");
+        assert_eq!(When::parse("9999999999999999y").unwrap_err().code(), "invalid-date");
+        assert_eq!(When::parse("99999999999d").unwrap().timestamp(now).unwrap_err().code(), "invalid-date");
+    }
+
+    #[test]
+    fn utc_days() {
+        // 23:30 UTC is already the next day east of UTC, today is still the 9th
+        let now = to_datetime(1636500600).unwrap(); // 2021-11-09 23:30
+        assert_eq!(When::parse("today").unwrap().timestamp(now).unwrap(), 1636416000);
+        assert_eq!(When::parse("2021-11-10").unwrap().timestamp(now).unwrap(), 1636416000 + 86400);
+    }
+}
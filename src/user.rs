@@ -0,0 +1,41 @@
+use notmuch::Database;
+
+use crate::{Result, address::Address};
+
+/// Who we are, from the [user] section of the notmuch config
+#[derive(Debug, Clone, Default)]
+pub struct User {
+    pub name: Option<String>,
+    // primary_email first, then other_email
+    pub addresses: Vec<String>,
+}
+
+impl User {
+    pub fn load(db: &Database) -> Result<User> {
+        let mut user = User::default();
+        let mut other = Vec::new();
+        for (key, value) in db.config_list("user.")? {
+            match key.as_str() {
+                "user.name" => user.name = Some(value),
+                "user.primary_email" => user.addresses.insert(0, value.trim().to_lowercase()),
+                // a list separated by ;
+                "user.other_email" => other.extend(value.split(';')
+                    .map(|x| x.trim().to_lowercase())
+                    .filter(|x| !x.is_empty())),
+                _ => {}
+            }
+        }
+        user.addresses.extend(other);
+        Ok(user)
+    }
+
+    pub fn is_me(&self, addr: &str) -> bool {
+        let addr = addr.to_lowercase();
+        self.addresses.contains(&addr)
+    }
+
+    /// If the first address in a header, like From, is one of ours
+    pub fn sent(&self, from: &str) -> bool {
+        Address::parse_list(from).first().is_some_and(|x| self.is_me(&x.addr))
+    }
+}